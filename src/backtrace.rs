@@ -0,0 +1,104 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+Backtraces from the frame pointer chain (debug builds use -Cforce-frame-pointers=yes).
+
+Each frame record is the StackFrame pushed by the prologue: x29 points to
+{ previous x29, x30 }. The exception trampoline pushes { interrupted x29, ELR }
+so a walk started from an ExceptionFrame begins at the faulting instruction.
+
+The chain is only followed while it stays within the boot stack given by
+PlatformInfo and goes up the stack: a corrupted x29 ends the walk instead of
+faulting again. Addresses are printed as RVAs, image relative, followed by the
+symbol when the image embeds its symbol table (see symbols.rs), otherwise they
+can be looked up in barekit.map (which shows them as Rva+Base).
+*/
+
+use core::arch::asm;
+
+use crate::platforms;
+use crate::processor::StackFrame;
+use crate::symbols;
+use crate::{print, println};
+
+const MAX_FRAMES: usize = 32;
+
+/// Bounds of the boot stack, None before the platform is known.
+pub fn boot_stack() -> Option<(u64, u64)> {
+    let info = platforms::current()?.get_info();
+    Some((info.boot_stack_top - info.boot_stack_capacity as u64, info.boot_stack_top))
+}
+
+/// Bounds of the boot stack and of the image, None before the platform is known.
+fn context() -> Option<(u64, u64, u64, u64)> {
+    let (stack_low, stack_high) = boot_stack()?;
+    let info = platforms::current()?.get_info();
+    Some((stack_low, stack_high, info.image_base, info.image_end))
+}
+
+/// Calls `f` with the return address of each frame record from `fp` on.
+pub fn walk(mut fp: u64, stack_low: u64, stack_high: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if fp & 0xf != 0 || fp < stack_low || fp + core::mem::size_of::<StackFrame>() as u64 > stack_high {
+            return;
+        }
+        let frame = unsafe { &*(fp as *const StackFrame) };
+        if frame.return_address == 0 {
+            return;
+        }
+        f(frame.return_address);
+        // the caller's record is higher on the stack
+        if frame.old_fp <= fp {
+            return;
+        }
+        fp = frame.old_fp;
+    }
+}
+
+/* the first return address is exact when it is the ELR of an exception frame */
+fn print_chain(fp: u64, exact_first: bool) {
+    let (stack_low, stack_high, image_base, image_end) = match context() {
+        Some(context) => context,
+        None => {
+            println!("backtrace: boot stack unknown");
+            return;
+        }
+    };
+    println!("backtrace (image at {:#x}):", image_base);
+    let mut depth = 0;
+    walk(fp, stack_low, stack_high, |address| {
+        // return addresses point after the call: the call site is 4 bytes before
+        let pc = if depth == 0 && exact_first { address } else { address.wrapping_sub(4) };
+        if pc >= image_base && pc < image_end {
+            print!("  #{:<2} rva {:#010x}", depth, pc - image_base);
+            symbols::print_symbol(pc);
+            println!();
+        }
+        else {
+            println!("  #{:<2} {:#018x} (outside the image)", depth, pc);
+        }
+        depth += 1;
+    });
+    if depth == 0 {
+        println!("  no frame record within the boot stack [{:#x}-{:#x}]", stack_low, stack_high);
+    }
+}
+
+/// Prints the chain of the exception frame record: starts with the faulting instruction.
+pub fn print_from_exception(stack_frame: &StackFrame) {
+    print_chain(stack_frame as *const StackFrame as u64, true);
+}
+
+/// Prints the caller's backtrace.
+#[inline(always)]
+pub fn print() {
+    let fp: u64;
+    unsafe {
+        asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+    print_chain(fp, false);
+}
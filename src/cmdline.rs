@@ -0,0 +1,51 @@
+/* 
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+    
+*/
+
+use alloc::string::String;
+
+/* Boot command line: from the EFI load options or /chosen/bootargs.
+   Made of space separated words, either flags ("monitor") or key=value pairs ("oom=reset"). */
+static mut CMDLINE: Option<String> = None;
+
+pub fn set(line: String) {
+    unsafe {
+        CMDLINE = Some(line);
+    }
+}
+
+pub fn get() -> &'static str {
+    unsafe {
+        match &*core::ptr::addr_of!(CMDLINE) {
+            Some(line) => line.as_str(),
+            None => ""
+        }
+    }
+}
+
+/// Value of the first `key=value` word, None if the key is not present.
+pub fn get_value(key: &str) -> Option<&'static str> {
+    for word in get().split_ascii_whitespace() {
+        if let Some((k, v)) = word.split_once('=') {
+            if k == key {
+                return Some(v);
+            }
+        }
+    }
+    None
+}
+
+/// True if the command line contains the word `key`, alone or as `key=...`.
+pub fn has_flag(key: &str) -> bool {
+    get().split_ascii_whitespace().any(|word| word == key || word.split_once('=').map(|(k, _)| k) == Some(key))
+}
+
+/// Parses a number written in hexadecimal (0x prefix) or decimal.
+pub fn parse_u64(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse::<u64>().ok()
+    }
+}
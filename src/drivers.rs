@@ -4,12 +4,30 @@
     
 */
 
+pub mod uart;
 pub mod pl011;
 pub mod ttybuffer;
 pub mod ttyefi;
 pub mod ns16550a;
+pub mod ramoops;
+pub mod cadence;
+pub mod lpuart;
+pub mod meson;
+pub mod virtio;
+pub mod virtio_console;
+pub mod font;
+pub mod framebuffer;
+pub mod registry;
+pub mod psci;
 
 pub use pl011::*;
 pub use ttybuffer::*;
 pub use ttyefi::*;
-pub use ns16550a::*;
\ No newline at end of file
+pub use ns16550a::*;
+pub use ramoops::*;
+pub use cadence::*;
+pub use lpuart::*;
+pub use meson::*;
+pub use virtio_console::*;
+pub use framebuffer::*;
+pub use psci::PsciDriver;
\ No newline at end of file
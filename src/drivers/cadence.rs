@@ -0,0 +1,108 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/* Cadence UART (Xilinx Zynq and ZynqMP, QEMU xlnx-zcu102). */
+
+use core::{fmt, sync::atomic::{AtomicU32, Ordering}, hint};
+use alloc::string::String;
+use alloc::boxed::Box;
+
+use fdt_rs::index::DevTreeIndexNode;
+
+use crate::log::{TTY, ConsoleInput};
+use crate::log::Logger;
+use crate::dt::DeviceTree;
+use crate::drivers::registry::{Driver, Device};
+
+pub const CADENCE_UART : &str = "cdns,uart-r1p12";
+pub const XILINX_UARTPS : &str = "xlnx,xuartps";
+
+// registers
+const UART_CR: u64 = 0x00;
+const UART_SR: u64 = 0x2c;
+const UART_FIFO: u64 = 0x30;
+
+// CR bits
+const CR_RX_EN: u32 = 1 << 2;
+const CR_RX_DIS: u32 = 1 << 3;
+const CR_TX_EN: u32 = 1 << 4;
+const CR_TX_DIS: u32 = 1 << 5;
+
+// SR bits
+const SR_RXEMPTY: u32 = 1 << 1;
+const SR_TXFULL: u32 = 1 << 4;
+
+pub struct CadenceOutput {
+    #[allow(dead_code)]
+    pub compatible: &'static str,
+    pub mmio_base: u64,
+}
+
+impl CadenceOutput {
+
+    /// Keeps the firmware line settings, only making sure both directions are enabled.
+    pub fn new(compatible: &'static str, mmio_base: u64) -> Option<Box<dyn Logger>> {
+        let driver = CadenceOutput { compatible, mmio_base };
+        let cr = driver.reg(UART_CR).load(Ordering::Acquire);
+        driver.reg(UART_CR).store((cr & !(CR_RX_DIS | CR_TX_DIS)) | CR_RX_EN | CR_TX_EN, Ordering::Release);
+        Some(Box::new(driver))
+    }
+
+    fn reg(&self, offset: u64) -> &AtomicU32 {
+        unsafe { &*((self.mmio_base + offset) as *const AtomicU32) }
+    }
+
+    fn putc(&self, c: u8) {
+        while self.reg(UART_SR).load(Ordering::Acquire) & SR_TXFULL != 0 {
+            hint::spin_loop();
+        }
+        self.reg(UART_FIFO).store(c as u32, Ordering::Release);
+    }
+}
+
+pub struct CadenceDriver;
+
+impl Driver for CadenceDriver {
+    fn name(&self) -> &'static str {
+        "cadence"
+    }
+    fn compatible(&self) -> &'static [&'static str] {
+        &[CADENCE_UART, XILINX_UARTPS]
+    }
+    fn start_console(&self, _devt: &DeviceTree, _node: &DevTreeIndexNode, device: &Device, _options: Option<&str>) -> Option<Box<dyn Logger>> {
+        CadenceOutput::new(device.compatible, device.base)
+    }
+}
+
+impl fmt::Write for CadenceOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            if c == b'\n' {
+                self.putc(b'\r');
+            }
+            self.putc(c);
+        }
+        Ok(())
+    }
+}
+
+impl TTY for CadenceOutput {
+    fn get_unprinted(&self) -> String {
+        return String::from("");
+    }
+    fn as_input(&mut self) -> Option<&mut dyn ConsoleInput> {
+        Some(self)
+    }
+}
+
+impl ConsoleInput for CadenceOutput {
+    fn try_read(&mut self) -> Option<u8> {
+        if self.reg(UART_SR).load(Ordering::Acquire) & SR_RXEMPTY != 0 {
+            return None;
+        }
+        Some(self.reg(UART_FIFO).load(Ordering::Acquire) as u8)
+    }
+}
@@ -0,0 +1,367 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+Text console on a linear framebuffer, for targets with a display and no UART.
+
+The framebuffer is the one left by EFI GraphicsOutputProtocol, which stays
+valid after ExitBootServices, or a simple-framebuffer node (usually under
+/chosen). Glyphs come from the embedded 8x13 font; the screen
+scrolls by moving the text rows up. The escape sequences understood are the
+ones log output uses:
+
+    ESC [ <n>;... m     colours: 0 reset, 1 bright, 30-37/90-97 foreground,
+                        40-47/100-107 background, 39/49 defaults
+    ESC [ 2 J           clear the screen
+    ESC [ H             cursor home
+
+Anything else in an escape sequence is dropped.
+*/
+
+use core::{fmt, ptr};
+use alloc::string::String;
+use alloc::boxed::Box;
+
+use fdt_rs::index::DevTreeIndexNode;
+use fdt_rs::prelude::{FallibleIterator, PropReader};
+
+use crate::dt::{DeviceTree, read_two_items};
+use crate::drivers::font::{FONT, FONT_WIDTH, FONT_HEIGHT, FONT_FIRST, FONT_LAST};
+use crate::log::TTY;
+use crate::log::Logger;
+use crate::drivers::registry::{Driver, Device};
+
+pub const SIMPLE_FRAMEBUFFER : &str = "simple-framebuffer";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    // 32 bits, blue in the lowest byte: GOP BGR, DT a8r8g8b8/x8r8g8b8
+    Xrgb8888,
+    // 32 bits, red in the lowest byte: GOP RGB, DT a8b8g8r8/x8b8g8r8
+    Xbgr8888,
+    Rgb565,
+}
+
+#[derive(Clone, Copy)]
+pub struct Framebuffer {
+    pub base: u64,
+    pub width: usize,
+    pub height: usize,
+    // bytes per line
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+impl PixelFormat {
+
+    /// The format named by the simple-framebuffer binding.
+    pub fn from_dt_name(name: &str) -> Option<PixelFormat> {
+        match name {
+            "a8r8g8b8" | "x8r8g8b8" => Some(PixelFormat::Xrgb8888),
+            "a8b8g8r8" | "x8b8g8r8" => Some(PixelFormat::Xbgr8888),
+            "r5g6b5" => Some(PixelFormat::Rgb565),
+            _ => None,
+        }
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            _ => 4,
+        }
+    }
+
+    /* from 0xRRGGBB */
+    fn encode(&self, rgb: u32) -> u32 {
+        let (r, g, b) = ((rgb >> 16) & 0xff, (rgb >> 8) & 0xff, rgb & 0xff);
+        match self {
+            PixelFormat::Xrgb8888 => rgb,
+            PixelFormat::Xbgr8888 => (b << 16) | (g << 8) | r,
+            PixelFormat::Rgb565 => ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3),
+        }
+    }
+}
+
+impl Framebuffer {
+
+    /// Reads a simple-framebuffer node, None if a property is missing or the
+    /// format is not supported.
+    pub fn from_node(devt: &DeviceTree, node: &DevTreeIndexNode) -> Option<Framebuffer> {
+        if !devt.is_compatible(node, SIMPLE_FRAMEBUFFER) {
+            return None;
+        }
+        if let Some(status) = devt.get_prop_by_name(node, "status") {
+            if !matches!(status.str(), Ok("okay") | Ok("ok")) {
+                return None;
+            }
+        }
+        // /chosen seldom has the cells properties: the defaults of the specification apply
+        let bus = node.parent()?;
+        let acells = devt.get_u32(&bus, "#address-cells").unwrap_or(2);
+        let scells = devt.get_u32(&bus, "#size-cells").unwrap_or(1);
+        let reg = read_two_items(devt.get_prop_by_name(node, "reg")?, acells, scells);
+        let format = devt.get_prop_by_name(node, "format")?.iter_str().next().ok()??;
+        Some(Framebuffer {
+            base: reg.first()?.base,
+            width: devt.get_u32(node, "width")? as usize,
+            height: devt.get_u32(node, "height")? as usize,
+            stride: devt.get_u32(node, "stride")? as usize,
+            format: PixelFormat::from_dt_name(format)?,
+        })
+    }
+
+    fn pixel_address(&self, x: usize, y: usize) -> u64 {
+        self.base + (y * self.stride + x * self.format.bytes_per_pixel()) as u64
+    }
+
+    fn put_pixel(&self, x: usize, y: usize, pixel: u32) {
+        let address = self.pixel_address(x, y);
+        unsafe {
+            if self.format == PixelFormat::Rgb565 {
+                ptr::write_volatile(address as *mut u16, pixel as u16);
+            }
+            else {
+                ptr::write_volatile(address as *mut u32, pixel);
+            }
+        }
+    }
+
+    fn fill_lines(&self, y: usize, lines: usize, pixel: u32) {
+        for line in y..y + lines {
+            for x in 0..self.width {
+                self.put_pixel(x, line, pixel);
+            }
+        }
+    }
+}
+
+const ESC: u8 = 0x1b;
+const MAX_PARAMETERS: usize = 4;
+
+// VGA like palette, normal then bright
+const PALETTE: [u32; 16] = [
+    0x000000, 0xaa0000, 0x00aa00, 0xaa5500, 0x0000aa, 0xaa00aa, 0x00aaaa, 0xaaaaaa,
+    0x555555, 0xff5555, 0x55ff55, 0xffff55, 0x5555ff, 0xff55ff, 0x55ffff, 0xffffff,
+];
+const DEFAULT_FOREGROUND: usize = 7;
+const DEFAULT_BACKGROUND: usize = 0;
+const TAB_WIDTH: usize = 8;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    Escape,
+    // control sequence introducer seen, parameters being collected
+    Csi,
+}
+
+pub struct FramebufferConsole {
+    framebuffer: Framebuffer,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    foreground: usize,
+    background: usize,
+    bright: bool,
+    escape: Escape,
+    parameters: [u16; MAX_PARAMETERS],
+    parameter_count: usize,
+}
+
+impl FramebufferConsole {
+
+    /// Clears the screen and starts writing at the top left corner.
+    pub fn new(framebuffer: Framebuffer) -> Option<Box<dyn Logger>> {
+        let columns = framebuffer.width / FONT_WIDTH;
+        let rows = framebuffer.height / FONT_HEIGHT;
+        if columns == 0 || rows == 0 {
+            return None;
+        }
+        let console = FramebufferConsole {
+            framebuffer,
+            columns,
+            rows,
+            column: 0,
+            row: 0,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bright: false,
+            escape: Escape::None,
+            parameters: [0; MAX_PARAMETERS],
+            parameter_count: 0,
+        };
+        console.clear();
+        Some(Box::new(console))
+    }
+
+    fn pixel(&self, color: usize) -> u32 {
+        self.framebuffer.format.encode(PALETTE[color])
+    }
+
+    fn clear(&self) {
+        self.framebuffer.fill_lines(0, self.framebuffer.height, self.pixel(self.background));
+    }
+
+    fn draw_glyph(&self, c: u8) {
+        let glyph = match c {
+            FONT_FIRST..=FONT_LAST => &FONT[(c - FONT_FIRST) as usize],
+            _ => &FONT[(b'?' - FONT_FIRST) as usize],
+        };
+        let foreground = self.foreground + if self.bright && self.foreground < 8 { 8 } else { 0 };
+        let (foreground, background) = (self.pixel(foreground), self.pixel(self.background));
+        let (x0, y0) = (self.column * FONT_WIDTH, self.row * FONT_HEIGHT);
+        for (y, bits) in glyph.iter().enumerate() {
+            for x in 0..FONT_WIDTH {
+                let pixel = if bits & (0x80 >> x) != 0 { foreground } else { background };
+                self.framebuffer.put_pixel(x0 + x, y0 + y, pixel);
+            }
+        }
+    }
+
+    /* moves the text up one row and blanks the last one */
+    fn scroll(&mut self) {
+        let fb = &self.framebuffer;
+        let row_bytes = FONT_HEIGHT * fb.stride;
+        unsafe {
+            ptr::copy(fb.pixel_address(0, FONT_HEIGHT) as *const u8, fb.base as *mut u8, (self.rows - 1) * row_bytes);
+        }
+        fb.fill_lines((self.rows - 1) * FONT_HEIGHT, FONT_HEIGHT, self.pixel(self.background));
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        }
+        else {
+            self.scroll();
+        }
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        // ESC [ m is ESC [ 0 m, the parameters beyond MAX_PARAMETERS are dropped
+        let count = self.parameter_count.clamp(1, MAX_PARAMETERS);
+        for &parameter in &self.parameters[..count] {
+            match parameter {
+                0 => {
+                    self.foreground = DEFAULT_FOREGROUND;
+                    self.background = DEFAULT_BACKGROUND;
+                    self.bright = false;
+                }
+                1 => self.bright = true,
+                22 => self.bright = false,
+                30..=37 => self.foreground = (parameter - 30) as usize,
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.background = (parameter - 40) as usize,
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = (parameter - 90) as usize + 8,
+                100..=107 => self.background = (parameter - 100) as usize + 8,
+                _ => {}
+            }
+        }
+    }
+
+    fn control_sequence(&mut self, c: u8) {
+        match c {
+            b'0'..=b'9' => {
+                if self.parameter_count == 0 {
+                    self.parameter_count = 1;
+                }
+                if self.parameter_count <= MAX_PARAMETERS {
+                    let p = &mut self.parameters[self.parameter_count - 1];
+                    *p = p.saturating_mul(10).saturating_add((c - b'0') as u16);
+                }
+                return;
+            }
+            b';' => {
+                // an empty first parameter is 0
+                self.parameter_count = core::cmp::max(self.parameter_count, 1) + 1;
+                return;
+            }
+            b'm' => self.select_graphic_rendition(),
+            b'J' if self.parameters[0] == 2 => self.clear(),
+            b'H' => {
+                self.column = 0;
+                self.row = 0;
+            }
+            _ => {}
+        }
+        self.escape = Escape::None;
+    }
+
+    fn putc(&mut self, c: u8) {
+        match self.escape {
+            Escape::Escape => {
+                if c == b'[' {
+                    self.escape = Escape::Csi;
+                    self.parameters = [0; MAX_PARAMETERS];
+                    self.parameter_count = 0;
+                }
+                else {
+                    self.escape = Escape::None;
+                }
+                return;
+            }
+            Escape::Csi => {
+                self.control_sequence(c);
+                return;
+            }
+            Escape::None => {}
+        }
+        match c {
+            ESC => self.escape = Escape::Escape,
+            b'\n' => self.new_line(),
+            b'\r' => self.column = 0,
+            b'\t' => {
+                self.column = core::cmp::min((self.column / TAB_WIDTH + 1) * TAB_WIDTH, self.columns - 1);
+            }
+            0x08 => self.column = self.column.saturating_sub(1),
+            _ => {
+                if self.column == self.columns {
+                    self.new_line();
+                }
+                self.draw_glyph(c);
+                self.column += 1;
+            }
+        }
+    }
+}
+
+pub struct FramebufferDriver;
+
+impl Driver for FramebufferDriver {
+    fn name(&self) -> &'static str {
+        SIMPLE_FRAMEBUFFER
+    }
+    fn compatible(&self) -> &'static [&'static str] {
+        &[SIMPLE_FRAMEBUFFER]
+    }
+    fn probe(&self, devt: &DeviceTree, node: &DevTreeIndexNode) -> Option<Device> {
+        let framebuffer = Framebuffer::from_node(devt, node)?;
+        Some(Device { compatible: SIMPLE_FRAMEBUFFER, base: framebuffer.base })
+    }
+    /* the console clears the screen: only done for the framebuffer actually used */
+    fn start_console(&self, devt: &DeviceTree, node: &DevTreeIndexNode, _device: &Device, _options: Option<&str>) -> Option<Box<dyn Logger>> {
+        FramebufferConsole::new(Framebuffer::from_node(devt, node)?)
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            // the font is ASCII only
+            self.putc(if c.is_ascii() { c as u8 } else { b'?' });
+        }
+        Ok(())
+    }
+}
+
+impl TTY for FramebufferConsole {
+    fn get_unprinted(&self) -> String {
+        return String::from("");
+    }
+}
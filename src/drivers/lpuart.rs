@@ -0,0 +1,109 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/* NXP i.MX 7ULP / 8 LPUART: the 32 bit register layout preceded by the
+   VERID, PARAM and GLOBAL registers. */
+
+use core::{fmt, sync::atomic::{AtomicU32, Ordering}, hint};
+use alloc::string::String;
+use alloc::boxed::Box;
+
+use fdt_rs::index::DevTreeIndexNode;
+
+use crate::log::{TTY, ConsoleInput};
+use crate::log::Logger;
+use crate::dt::DeviceTree;
+use crate::drivers::registry::{Driver, Device};
+
+pub const IMX8QM_LPUART : &str = "fsl,imx8qm-lpuart";
+pub const IMX7ULP_LPUART : &str = "fsl,imx7ulp-lpuart";
+
+// registers
+const LPUART_STAT: u64 = 0x14;
+const LPUART_CTRL: u64 = 0x18;
+const LPUART_DATA: u64 = 0x1c;
+
+// STAT bits
+const STAT_RDRF: u32 = 1 << 21;
+const STAT_TDRE: u32 = 1 << 23;
+
+// CTRL bits
+const CTRL_RE: u32 = 1 << 18;
+const CTRL_TE: u32 = 1 << 19;
+
+pub struct LpuartOutput {
+    #[allow(dead_code)]
+    pub compatible: &'static str,
+    pub mmio_base: u64,
+}
+
+impl LpuartOutput {
+
+    /// Keeps the firmware line settings, only making sure both directions are enabled.
+    pub fn new(compatible: &'static str, mmio_base: u64) -> Option<Box<dyn Logger>> {
+        let driver = LpuartOutput { compatible, mmio_base };
+        let ctrl = driver.reg(LPUART_CTRL).load(Ordering::Acquire);
+        if ctrl & (CTRL_RE | CTRL_TE) != CTRL_RE | CTRL_TE {
+            driver.reg(LPUART_CTRL).store(ctrl | CTRL_RE | CTRL_TE, Ordering::Release);
+        }
+        Some(Box::new(driver))
+    }
+
+    fn reg(&self, offset: u64) -> &AtomicU32 {
+        unsafe { &*((self.mmio_base + offset) as *const AtomicU32) }
+    }
+
+    fn putc(&self, c: u8) {
+        while self.reg(LPUART_STAT).load(Ordering::Acquire) & STAT_TDRE == 0 {
+            hint::spin_loop();
+        }
+        self.reg(LPUART_DATA).store(c as u32, Ordering::Release);
+    }
+}
+
+pub struct LpuartDriver;
+
+impl Driver for LpuartDriver {
+    fn name(&self) -> &'static str {
+        "lpuart"
+    }
+    fn compatible(&self) -> &'static [&'static str] {
+        &[IMX8QM_LPUART, IMX7ULP_LPUART]
+    }
+    fn start_console(&self, _devt: &DeviceTree, _node: &DevTreeIndexNode, device: &Device, _options: Option<&str>) -> Option<Box<dyn Logger>> {
+        LpuartOutput::new(device.compatible, device.base)
+    }
+}
+
+impl fmt::Write for LpuartOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            if c == b'\n' {
+                self.putc(b'\r');
+            }
+            self.putc(c);
+        }
+        Ok(())
+    }
+}
+
+impl TTY for LpuartOutput {
+    fn get_unprinted(&self) -> String {
+        return String::from("");
+    }
+    fn as_input(&mut self) -> Option<&mut dyn ConsoleInput> {
+        Some(self)
+    }
+}
+
+impl ConsoleInput for LpuartOutput {
+    fn try_read(&mut self) -> Option<u8> {
+        if self.reg(LPUART_STAT).load(Ordering::Acquire) & STAT_RDRF == 0 {
+            return None;
+        }
+        Some(self.reg(LPUART_DATA).load(Ordering::Acquire) as u8)
+    }
+}
@@ -0,0 +1,108 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/* Amlogic Meson GX UART (S905, S912, A311D...). */
+
+use core::{fmt, sync::atomic::{AtomicU32, Ordering}, hint};
+use alloc::string::String;
+use alloc::boxed::Box;
+
+use fdt_rs::index::DevTreeIndexNode;
+
+use crate::log::{TTY, ConsoleInput};
+use crate::log::Logger;
+use crate::dt::DeviceTree;
+use crate::drivers::registry::{Driver, Device};
+
+pub const MESON_GX_UART : &str = "amlogic,meson-gx-uart";
+
+// registers
+const AML_UART_WFIFO: u64 = 0x00;
+const AML_UART_RFIFO: u64 = 0x04;
+const AML_UART_CONTROL: u64 = 0x08;
+const AML_UART_STATUS: u64 = 0x0c;
+
+// CONTROL bits
+const CONTROL_TX_EN: u32 = 1 << 12;
+const CONTROL_RX_EN: u32 = 1 << 13;
+
+// STATUS bits
+const STATUS_RX_EMPTY: u32 = 1 << 20;
+const STATUS_TX_FULL: u32 = 1 << 21;
+
+pub struct MesonOutput {
+    #[allow(dead_code)]
+    pub compatible: &'static str,
+    pub mmio_base: u64,
+}
+
+impl MesonOutput {
+
+    /// Keeps the firmware line settings, only making sure both directions are enabled.
+    pub fn new(compatible: &'static str, mmio_base: u64) -> Option<Box<dyn Logger>> {
+        let driver = MesonOutput { compatible, mmio_base };
+        let control = driver.reg(AML_UART_CONTROL).load(Ordering::Acquire);
+        if control & (CONTROL_TX_EN | CONTROL_RX_EN) != CONTROL_TX_EN | CONTROL_RX_EN {
+            driver.reg(AML_UART_CONTROL).store(control | CONTROL_TX_EN | CONTROL_RX_EN, Ordering::Release);
+        }
+        Some(Box::new(driver))
+    }
+
+    fn reg(&self, offset: u64) -> &AtomicU32 {
+        unsafe { &*((self.mmio_base + offset) as *const AtomicU32) }
+    }
+
+    fn putc(&self, c: u8) {
+        while self.reg(AML_UART_STATUS).load(Ordering::Acquire) & STATUS_TX_FULL != 0 {
+            hint::spin_loop();
+        }
+        self.reg(AML_UART_WFIFO).store(c as u32, Ordering::Release);
+    }
+}
+
+pub struct MesonDriver;
+
+impl Driver for MesonDriver {
+    fn name(&self) -> &'static str {
+        "meson"
+    }
+    fn compatible(&self) -> &'static [&'static str] {
+        &[MESON_GX_UART]
+    }
+    fn start_console(&self, _devt: &DeviceTree, _node: &DevTreeIndexNode, device: &Device, _options: Option<&str>) -> Option<Box<dyn Logger>> {
+        MesonOutput::new(device.compatible, device.base)
+    }
+}
+
+impl fmt::Write for MesonOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            if c == b'\n' {
+                self.putc(b'\r');
+            }
+            self.putc(c);
+        }
+        Ok(())
+    }
+}
+
+impl TTY for MesonOutput {
+    fn get_unprinted(&self) -> String {
+        return String::from("");
+    }
+    fn as_input(&mut self) -> Option<&mut dyn ConsoleInput> {
+        Some(self)
+    }
+}
+
+impl ConsoleInput for MesonOutput {
+    fn try_read(&mut self) -> Option<u8> {
+        if self.reg(AML_UART_STATUS).load(Ordering::Acquire) & STATUS_RX_EMPTY != 0 {
+            return None;
+        }
+        Some(self.reg(AML_UART_RFIFO).load(Ordering::Acquire) as u8)
+    }
+}
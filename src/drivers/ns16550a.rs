@@ -4,126 +4,253 @@
     
 */
 
-use core::{fmt, sync::atomic::{AtomicU8, AtomicU32, Ordering}, hint};
+use core::{fmt, ptr, sync::atomic::{AtomicU8, AtomicU16, AtomicU32, Ordering}, hint};
 use alloc::string::String;
 use alloc::boxed::Box;
-use crate::alloc::borrow::ToOwned;
 
-use crate::{log::TTY, dt::DeviceTree};
+use crate::{log::{TTY, ConsoleInput}, dt::DeviceTree};
+use crate::drivers::uart::{LineConfig, Parity};
+use crate::drivers::registry::{Driver, Device};
 use crate::log::Logger;
+use crate::log;
+use crate::warn;
 
-use fdt_rs::{common::prop::StringPropIter, index::DevTreeIndexNode};
-use fdt_rs::prelude::FallibleIterator;
-use fdt_rs::prelude::PropReader;
+use fdt_rs::index::DevTreeIndexNode;
 
 #[cfg(feature = "early_print")]
 use crate::print::_early_print_s;
 use crate::early_prints;
 
-pub struct NS16550Output<'a> {
+pub struct NS16550Output {
     #[allow(dead_code)]
-    compatible: String,
-    is_32: bool,
-    data_reg : &'a mut AtomicU8,
-    flag_reg : &'a mut AtomicU8,
-    data_reg32 : &'a mut AtomicU32,
-    flag_reg32 : &'a mut AtomicU32,
+    compatible: &'static str,
+    mmio_base: u64,
+    reg_io: u32,
+    reg_shift: u32,
+    designware: bool,
 }
 
 pub const BROADCOM_BCM2835 : &str = "brcm,bcm2835-aux-uart";
 pub const NS16550 : &str = "ns16550a";
 pub const DESIGNWARE : &str = "snps,dw-apb-uart";
 
-impl NS16550Output<'_> {
-
-    pub fn new(compatible: String, mmio_base: u64, devt: &DeviceTree, node: &DevTreeIndexNode) -> Option<Box<dyn Logger>> {
-        Some(Box::new(
-            unsafe {
-                let mut reg_io: u32 = 1;
-                let mut reg_shift: u32 = 0;
-                if compatible.eq_ignore_ascii_case(BROADCOM_BCM2835) {
-                    reg_shift = 2;
-                } else if compatible.eq_ignore_ascii_case(NS16550) {
-                    reg_shift = 0;
-                    reg_io = 1;
-                } else if compatible.eq_ignore_ascii_case(DESIGNWARE) {
-                    let reg_io_prop = devt.get_prop_by_name(&node, "reg-io-width").unwrap();
-                    reg_io = reg_io_prop.u32(0).unwrap();
-                    let reg_shit_prop = devt.get_prop_by_name(&node, "reg-shift").unwrap();
-                    reg_shift = reg_shit_prop.u32(0).unwrap();
-                }
-                let driver = NS16550Output {
-                    compatible,
-                    is_32: reg_io == 4, // by default, registers are 8 bits
-                    data_reg: AtomicU8::from_mut(&mut *(mmio_base as *mut u8)),
-                    flag_reg: AtomicU8::from_mut(&mut *((mmio_base + (5 << reg_shift)) as *mut u8)),
-                    data_reg32: AtomicU32::from_mut(&mut *(mmio_base as *mut u32)),
-                    flag_reg32: AtomicU32::from_mut(&mut *((mmio_base + (5 << reg_shift)) as *mut u32)),
-                };
-                early_prints!("ns16550 driver reg_io: % \n", reg_io as u64);
-                early_prints!("ns16550 driver reg_shift: % \n", reg_shift as u64);
-                early_prints!("ns16550 driver data_reg: % \n", mmio_base);
-                early_prints!("ns16550 driver flag_reg: % \n", mmio_base + (5 << reg_shift));
-                driver
+// 8250 family compatibles
+const COMPATIBLES: [&str; 14] = [
+    NS16550,
+    "ns16550",
+    "ns16450",
+    "ns16750",
+    "ns16850",
+    "ns8250",
+    "ns8250_mmio32",
+    DESIGNWARE,
+    BROADCOM_BCM2835,
+    "nvidia,tegra20-uart",
+    "ti,omap4-uart",
+    "ti,omap3-uart",
+    "marvell,armada-38x-uart",
+    "mediatek,mt6577-uart",
+];
+
+/* (reg_io, reg_shift) when reg-io-width and reg-shift are absent */
+fn default_layout(compatible: &str) -> (u32, u32) {
+    match compatible {
+        "ns8250_mmio32" | DESIGNWARE | "marvell,armada-38x-uart" | "mediatek,mt6577-uart" => (4, 2),
+        BROADCOM_BCM2835 | "nvidia,tegra20-uart" | "ti,omap4-uart" | "ti,omap3-uart" => (1, 2),
+        _ => (1, 0),
+    }
+}
+
+// registers, in reg_shift units
+const UART_RBR: u64 = 0;
+const UART_THR: u64 = 0;
+const UART_DLL: u64 = 0;
+const UART_IER: u64 = 1;
+const UART_DLM: u64 = 1;
+const UART_IIR: u64 = 2;
+const UART_FCR: u64 = 2;
+const UART_LCR: u64 = 3;
+const UART_MCR: u64 = 4;
+const UART_LSR: u64 = 5;
+// DesignWare UART status register
+const DW_UART_USR: u64 = 31;
+
+// LSR bits
+const LSR_DR: u32 = 1 << 0;
+const LSR_THRE: u32 = 1 << 5;
+const LSR_TEMT: u32 = 1 << 6;
+
+// LCR bits
+const LCR_STOP2: u32 = 1 << 2;
+const LCR_PARITY: u32 = 1 << 3;
+const LCR_EVEN: u32 = 1 << 4;
+const LCR_DLAB: u32 = 1 << 7;
+
+const FCR_ENABLE_AND_CLEAR: u32 = 0x07;
+const MCR_DTR_RTS: u32 = 0x03;
+const IIR_BUSY_DETECT: u32 = 0x07;
+const USR_BUSY: u32 = 1 << 0;
+
+// LCR writes retried while a DesignWare UART reports busy
+const DW_LCR_RETRIES: usize = 1000;
+
+// storage for a console registered without the heap
+static mut STATIC_CONSOLE: Option<NS16550Output> = None;
+
+impl NS16550Output {
+
+    /// The register layout comes from reg-io-width and reg-shift, with per
+    /// compatible defaults. The line is programmed when the UART clock
+    /// (clock-frequency or clocks) and the speed (stdout-path options or
+    /// current-speed) are both known, otherwise the firmware settings are kept.
+    /// None for a reg-io-width other than 1, 2 or 4.
+    pub fn new(compatible: &'static str, mmio_base: u64, devt: &DeviceTree, node: &DevTreeIndexNode, options: Option<&str>) -> Option<Box<dyn Logger>> {
+        let (default_io, default_shift) = default_layout(compatible);
+        let reg_io = devt.get_u32(node, "reg-io-width").unwrap_or(default_io);
+        let reg_shift = devt.get_u32(node, "reg-shift").unwrap_or(default_shift);
+        if !matches!(reg_io, 1 | 2 | 4) {
+            warn!("{}: unsupported reg-io-width {}", compatible, reg_io);
+            return None;
+        }
+        let mut driver = Self::build(compatible, mmio_base, reg_io, reg_shift);
+        early_prints!("ns16550 driver reg_io: % \n", reg_io as u64);
+        early_prints!("ns16550 driver reg_shift: % \n", reg_shift as u64);
+        early_prints!("ns16550 driver data_reg: % \n", mmio_base);
+        early_prints!("ns16550 driver flag_reg: % \n", mmio_base + (UART_LSR << reg_shift));
+
+        let config = match options.and_then(LineConfig::parse) {
+            Some(config) => Some(config),
+            None => devt.get_u32(node, "current-speed").filter(|&baud| baud != 0)
+                .map(|baud| LineConfig { baud, parity: Parity::None, data_bits: 8, stop_bits: 1 }),
+        };
+        let clock = devt.get_clock_frequency(node, "baudclk");
+        // the mini UART of the BCM2835 has no divisor latch
+        if let (Some(clock), Some(config), false) = (clock, config, compatible == BROADCOM_BCM2835) {
+            driver.setup(clock, &config);
+        }
+        Some(Box::new(driver))
+    }
+
+    fn build(compatible: &'static str, mmio_base: u64, reg_io: u32, reg_shift: u32) -> NS16550Output {
+        NS16550Output {
+            compatible,
+            mmio_base,
+            reg_io,
+            reg_shift,
+            designware: compatible == DESIGNWARE,
+        }
+    }
+
+    /// Makes the UART the log target without allocating: usable before heap_init.
+    pub fn register_static(compatible: &'static str, mmio_base: u64, reg_io: u32, reg_shift: u32) {
+        unsafe {
+            let console = &mut *ptr::addr_of_mut!(STATIC_CONSOLE);
+            log::set_static_target(console.insert(Self::build(compatible, mmio_base, reg_io, reg_shift)));
+        }
+    }
+
+    /* registers are accessed with reg-io-width bytes: 1, 2 or 4, checked by new */
+    fn read(&self, register: u64) -> u32 {
+        let address = self.mmio_base + (register << self.reg_shift);
+        unsafe {
+            match self.reg_io {
+                4 => (*(address as *const AtomicU32)).load(Ordering::Acquire),
+                2 => (*(address as *const AtomicU16)).load(Ordering::Acquire) as u32,
+                _ => (*(address as *const AtomicU8)).load(Ordering::Acquire) as u32,
             }
-        ))
-    }
-
-    pub fn from_mmio(compatible: &str, mmio_base: u64, reg_io: u32, reg_shift: u32) -> Option<Box<dyn Logger>> {
-        Some(Box::new(
-            unsafe {
-                let driver = NS16550Output {
-                    compatible: compatible.to_owned(),
-                    is_32: reg_io == 4, // by default, registers are 8 bits
-                    data_reg: AtomicU8::from_mut(&mut *(mmio_base as *mut u8)),
-                    flag_reg: AtomicU8::from_mut(&mut *((mmio_base + (5 << reg_shift)) as *mut u8)),
-                    data_reg32: AtomicU32::from_mut(&mut *(mmio_base as *mut u32)),
-                    flag_reg32: AtomicU32::from_mut(&mut *((mmio_base + (5 << reg_shift)) as *mut u32)),
-                };
-                driver
+        }
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        let address = self.mmio_base + (register << self.reg_shift);
+        unsafe {
+            match self.reg_io {
+                4 => (*(address as *const AtomicU32)).store(value, Ordering::Release),
+                2 => (*(address as *const AtomicU16)).store(value as u16, Ordering::Release),
+                _ => (*(address as *const AtomicU8)).store(value as u8, Ordering::Release),
             }
-        ))
+        }
     }
-    
-    pub fn is_compatible(mut candidates: StringPropIter) -> Option<String> {
-        while let Some(s) = candidates.next().unwrap() {
-            early_prints!("ns16550 driver checking: ", 0);
-            early_prints!(s, 0);
-            early_prints!("\n", 0);
-            if s.eq(NS16550) {
-                return Some(String::from(NS16550));
+
+    /// Waits until everything queued has left the shift register.
+    pub fn wait_tx_idle(&self) {
+        while self.read(UART_LSR) & LSR_TEMT == 0 {
+            hint::spin_loop();
+        }
+    }
+
+    /* A DesignWare UART ignores LCR writes while busy and raises a busy detect
+       interrupt instead: drain the receiver, acknowledge through USR and retry
+       until the value sticks. */
+    fn write_lcr(&self, value: u32) {
+        if !self.designware {
+            self.write(UART_LCR, value);
+            return;
+        }
+        for _ in 0..DW_LCR_RETRIES {
+            if self.read(DW_UART_USR) & USR_BUSY != 0 {
+                self.write(UART_FCR, FCR_ENABLE_AND_CLEAR);
+                self.read(UART_RBR);
             }
-            else if s.eq(BROADCOM_BCM2835) {
-                return Some(String::from(BROADCOM_BCM2835));
-            } 
-            else if s.eq(DESIGNWARE) {
-                return Some(String::from(DESIGNWARE));
+            self.write(UART_LCR, value);
+            if self.read(UART_IIR) & 0x0f == IIR_BUSY_DETECT {
+                self.read(DW_UART_USR);
             }
+            if self.read(UART_LCR) == value {
+                return;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    fn setup(&mut self, clock: u32, config: &LineConfig) {
+        let divisor = (clock + 8 * config.baud) / (16 * config.baud);
+        if divisor == 0 || divisor > 0xffff {
+            return;
+        }
+        let mut lcr = config.data_bits as u32 - 5;
+        match config.parity {
+            Parity::None => {},
+            Parity::Odd => lcr |= LCR_PARITY,
+            Parity::Even => lcr |= LCR_PARITY | LCR_EVEN,
+        }
+        if config.stop_bits == 2 {
+            lcr |= LCR_STOP2;
         }
-        return None;
+
+        self.wait_tx_idle();
+        self.write(UART_IER, 0);
+        self.write_lcr(lcr | LCR_DLAB);
+        self.write(UART_DLL, divisor & 0xff);
+        self.write(UART_DLM, divisor >> 8);
+        self.write_lcr(lcr);
+        self.write(UART_FCR, FCR_ENABLE_AND_CLEAR);
+        self.write(UART_MCR, MCR_DTR_RTS);
+    }
+}
+
+pub struct NS16550Driver;
+
+impl Driver for NS16550Driver {
+    fn name(&self) -> &'static str {
+        "ns16550"
+    }
+    fn compatible(&self) -> &'static [&'static str] {
+        &COMPATIBLES
+    }
+    fn start_console(&self, devt: &DeviceTree, node: &DevTreeIndexNode, device: &Device, options: Option<&str>) -> Option<Box<dyn Logger>> {
+        NS16550Output::new(device.compatible, device.base, devt, node, options)
     }
 }
 
-impl fmt::Write for NS16550Output<'_> {
+impl fmt::Write for NS16550Output {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for mut c in s.chars() {
             loop {
-                if self.is_32 {
-                    loop {
-                        let flags = self.flag_reg32.load(Ordering::Acquire);
-                        if flags & 1<<5 != 0 { break; }
-                        hint::spin_loop();
-                    }
-                    self.data_reg32.store(c as u32, Ordering::Release);
-                }
-                else {
-                    loop {
-                        let flags = self.flag_reg.load(Ordering::Acquire);
-                        if flags & 1<<5 != 0 { break; }
-                        hint::spin_loop();
-                    }
-                    self.data_reg.store(c as u8, Ordering::Release);
+                while self.read(UART_LSR) & LSR_THRE == 0 {
+                    hint::spin_loop();
                 }
+                self.write(UART_THR, c as u32);
     
                 if c == '\n' 
                     { c = '\r'; }
@@ -135,8 +262,20 @@ impl fmt::Write for NS16550Output<'_> {
     }
 }
 
-impl TTY for NS16550Output<'_> {
+impl TTY for NS16550Output {
     fn get_unprinted(&self) -> String {
         return String::from("");
     }
+    fn as_input(&mut self) -> Option<&mut dyn ConsoleInput> {
+        Some(self)
+    }
+}
+
+impl ConsoleInput for NS16550Output {
+    fn try_read(&mut self) -> Option<u8> {
+        if self.read(UART_LSR) & LSR_DR == 0 {
+            return None;
+        }
+        Some(self.read(UART_RBR) as u8)
+    }
 }
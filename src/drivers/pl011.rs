@@ -7,59 +7,146 @@
 use core::{fmt, sync::atomic::{AtomicU32, Ordering}, hint};
 use alloc::string::String;
 use alloc::boxed::Box;
-use crate::alloc::borrow::ToOwned;
 
-use fdt_rs::common::prop::StringPropIter;
-use fdt_rs::prelude::FallibleIterator;
+use fdt_rs::index::DevTreeIndexNode;
 
-use crate::log::TTY;
+use crate::dt::DeviceTree;
+use crate::drivers::uart::{LineConfig, Parity};
+use crate::drivers::registry::{Driver, Device};
+
+use crate::log::{TTY, ConsoleInput};
 use crate::log::Logger;
 
-#[allow(dead_code)]
 pub const PL011 : &str = "arm,pl011";
+pub const SBSA_UART : &str = "arm,sbsa-uart";
+
+// registers
+const UARTFR: u64 = 0x18;
+const UARTIBRD: u64 = 0x24;
+const UARTFBRD: u64 = 0x28;
+const UARTLCR_H: u64 = 0x2c;
+const UARTCR: u64 = 0x30;
+const UARTIMSC: u64 = 0x38;
+const UARTICR: u64 = 0x44;
+
+// FR bits
+const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+const FR_TXFE: u32 = 1 << 7;
+
+// LCR_H bits
+const LCR_H_PEN: u32 = 1 << 1;
+const LCR_H_EPS: u32 = 1 << 2;
+const LCR_H_STP2: u32 = 1 << 3;
+const LCR_H_FEN: u32 = 1 << 4;
+const LCR_H_WLEN_SHIFT: u32 = 5;
+
+// CR bits
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+const ICR_ALL: u32 = 0x7ff;
 
 pub struct PL011Output<'a> {
-    pub compatible: String,
+    pub compatible: &'static str,
+    pub mmio_base: u64,
     pub data_reg : &'a mut AtomicU32,
     pub flag_reg : &'a mut AtomicU32
 }
 
 impl PL011Output<'_> {
 
-    pub fn new(compatible: String, mmio_base: u64) -> Option<Box<dyn Logger>> {
-        Some(Box::new(
-            unsafe {
-                PL011Output {
-                    compatible,
-                    data_reg: AtomicU32::from_mut(&mut *(mmio_base as *mut u32)),
-                    flag_reg: AtomicU32::from_mut(&mut *((mmio_base + 0x18) as *mut u32)),
-                }
+    fn build(compatible: &'static str, mmio_base: u64) -> PL011Output<'static> {
+        unsafe {
+            PL011Output {
+                compatible,
+                mmio_base,
+                data_reg: AtomicU32::from_mut(&mut *(mmio_base as *mut u32)),
+                flag_reg: AtomicU32::from_mut(&mut *((mmio_base + UARTFR) as *mut u32)),
             }
-        ))
+        }
     }
-    #[allow(dead_code)]
-    pub fn from_mmio(compatible: &str, mmio_base: u64, _reg_io: u32, _reg_shift: u32) -> Option<Box<dyn Logger>> {
-        Some(Box::new(
-            unsafe {
-                let driver = PL011Output {
-                    compatible: compatible.to_owned(),
-                    data_reg: AtomicU32::from_mut(&mut *(mmio_base as *mut u32)),
-                    flag_reg: AtomicU32::from_mut(&mut *((mmio_base + 0x18) as *mut u32)),
-                };
-                driver
+
+    /// Programs and enables the UART when its clock is known: barekit may be the
+    /// first to use it. The line comes from the stdout-path options (`115200n8`),
+    /// else current-speed, else 115200n8. Without the clock the firmware settings are kept.
+    pub fn new(compatible: &'static str, mmio_base: u64, devt: &DeviceTree, node: &DevTreeIndexNode, options: Option<&str>) -> Option<Box<dyn Logger>> {
+        let mut driver = Self::build(compatible, mmio_base);
+        // the SBSA generic UART has a fixed configuration
+        if compatible != SBSA_UART {
+            if let Some(clock) = devt.get_clock_frequency(node, "uartclk") {
+                let config = options.and_then(LineConfig::parse)
+                    .or_else(|| devt.get_u32(node, "current-speed").filter(|&baud| baud != 0).map(LineConfig::with_baud))
+                    .unwrap_or(LineConfig::DEFAULT);
+                driver.setup(clock, &config);
             }
-        ))
+        }
+        Some(Box::new(driver))
     }
-    pub fn is_compatible(mut candidates: StringPropIter) -> Option<String> {
-        while let Some(s) = candidates.next().unwrap() {
-            if s.eq("arm,pl011") {
-                return Some(String::from("arm,pl011"));
-            }
-            else if s.eq("arm,sbsa-uart") {
-                return Some(String::from("arm,sbsa-uart"));
-            } 
+    #[allow(dead_code)]
+    pub fn from_mmio(compatible: &'static str, mmio_base: u64, _reg_io: u32, _reg_shift: u32) -> Option<Box<dyn Logger>> {
+        Some(Box::new(Self::build(compatible, mmio_base)))
+    }
+    fn reg(&self, offset: u64) -> &AtomicU32 {
+        unsafe { &*((self.mmio_base + offset) as *const AtomicU32) }
+    }
+
+    /// Waits until everything queued has left the shift register.
+    pub fn wait_tx_idle(&self) {
+        loop {
+            let flags = self.flag_reg.load(Ordering::Acquire);
+            if flags & FR_BUSY == 0 && flags & FR_TXFE != 0 { break; }
+            hint::spin_loop();
+        }
+    }
+
+    /* the sequence of the PL011 TRM: disable, program, enable. A speed out of
+       reach of the clock keeps the current divisors, the UART is enabled anyway */
+    fn setup(&mut self, clock: u32, config: &LineConfig) {
+        // divisor in 1/64th: clock / (16 * baud) * 64, rounded
+        let divisor = (clock as u64 * 4 + config.baud as u64 / 2) / config.baud as u64;
+        let ibrd = (divisor >> 6) as u32;
+        let fbrd = (divisor & 0x3f) as u32;
+        let divisor_ok = ibrd != 0 && ibrd <= 0xffff;
+        let mut lcr_h = LCR_H_FEN | ((config.data_bits as u32 - 5) << LCR_H_WLEN_SHIFT);
+        match config.parity {
+            Parity::None => {},
+            Parity::Odd => lcr_h |= LCR_H_PEN,
+            Parity::Even => lcr_h |= LCR_H_PEN | LCR_H_EPS,
+        }
+        if config.stop_bits == 2 {
+            lcr_h |= LCR_H_STP2;
+        }
+
+        self.wait_tx_idle();
+        self.reg(UARTCR).store(0, Ordering::Release);
+        // flushes the FIFOs
+        self.reg(UARTLCR_H).store(0, Ordering::Release);
+        if divisor_ok {
+            self.reg(UARTIBRD).store(ibrd, Ordering::Release);
+            self.reg(UARTFBRD).store(fbrd, Ordering::Release);
         }
-        return None;
+        // the divisors are latched by the LCR_H write
+        self.reg(UARTLCR_H).store(lcr_h, Ordering::Release);
+        self.reg(UARTIMSC).store(0, Ordering::Release);
+        self.reg(UARTICR).store(ICR_ALL, Ordering::Release);
+        self.reg(UARTCR).store(CR_UARTEN | CR_TXE | CR_RXE, Ordering::Release);
+    }
+}
+
+pub struct PL011Driver;
+
+impl Driver for PL011Driver {
+    fn name(&self) -> &'static str {
+        "pl011"
+    }
+    fn compatible(&self) -> &'static [&'static str] {
+        &[PL011, SBSA_UART]
+    }
+    fn start_console(&self, devt: &DeviceTree, node: &DevTreeIndexNode, device: &Device, options: Option<&str>) -> Option<Box<dyn Logger>> {
+        PL011Output::new(device.compatible, device.base, devt, node, options)
     }
 }
 
@@ -69,7 +156,7 @@ impl fmt::Write for PL011Output<'_> {
 
             loop {
                 let flags = self.flag_reg.load(Ordering::Acquire);
-                if flags & 1<<4 != 0 { break; }
+                if flags & FR_TXFF == 0 { break; }
                 hint::spin_loop();
             }
             self.data_reg.store(c as u32, Ordering::Release);
@@ -82,4 +169,16 @@ impl TTY for PL011Output<'_> {
     fn get_unprinted(&self) -> String {
         return String::from("");
     }
+    fn as_input(&mut self) -> Option<&mut dyn ConsoleInput> {
+        Some(self)
+    }
+}
+
+impl ConsoleInput for PL011Output<'_> {
+    fn try_read(&mut self) -> Option<u8> {
+        if self.flag_reg.load(Ordering::Acquire) & FR_RXFE != 0 {
+            return None;
+        }
+        Some(self.data_reg.load(Ordering::Acquire) as u8)
+    }
 }
@@ -0,0 +1,92 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+PSCI, the power interface of the firmware running at a higher EL, described by
+the /psci node: its method tells whether calls go through SMC (EL3 firmware)
+or HVC (hypervisor). Started by the bind pass so that the platforms without a
+reset of their own (bare metal EL1/EL2, secure payloads) can reset the board.
+
+A conduit that targets the current EL or a lower one would trap into barekit
+itself: it is ignored, so at EL3 there is no PSCI.
+*/
+
+use core::arch::asm;
+use core::ptr;
+
+use fdt_rs::index::DevTreeIndexNode;
+use fdt_rs::prelude::PropReader;
+
+use crate::dt::DeviceTree;
+use crate::drivers::registry::{Driver, Device};
+use crate::processor;
+use crate::warn;
+
+pub const PSCI_DRIVER : &str = "psci";
+
+const PSCI_SYSTEM_RESET: u64 = 0x8400_0009;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Conduit {
+    Smc,
+    Hvc,
+}
+
+static mut CONDUIT: Option<Conduit> = None;
+
+fn call(conduit: Conduit, function: u64) -> u64 {
+    let mut x0 = function;
+    unsafe {
+        match conduit {
+            Conduit::Smc => asm!("smc #0", inout("x0") x0, clobber_abi("C")),
+            Conduit::Hvc => asm!("hvc #0", inout("x0") x0, clobber_abi("C")),
+        }
+    }
+    x0
+}
+
+/// True when a PSCI implementation can be called.
+pub fn available() -> bool {
+    unsafe { ptr::read(ptr::addr_of!(CONDUIT)).is_some() }
+}
+
+/// Resets the board, returns only when there is no PSCI or it refused.
+pub fn system_reset() {
+    if let Some(conduit) = unsafe { CONDUIT } {
+        call(conduit, PSCI_SYSTEM_RESET);
+    }
+}
+
+pub struct PsciDriver;
+
+impl Driver for PsciDriver {
+    fn name(&self) -> &'static str {
+        PSCI_DRIVER
+    }
+    fn compatible(&self) -> &'static [&'static str] {
+        &["arm,psci-1.0", "arm,psci-0.2", "arm,psci"]
+    }
+    /* a firmware interface: no registers */
+    fn probe(&self, devt: &DeviceTree, node: &DevTreeIndexNode) -> Option<Device> {
+        let compatible = self.matches(devt, node)?;
+        Some(Device { compatible, base: 0 })
+    }
+    fn attach(&self, devt: &DeviceTree, node: &DevTreeIndexNode, _device: &Device) -> bool {
+        let conduit = match devt.get_prop_by_name(node, "method").map(|p| p.str()) {
+            Some(Ok("smc")) => (Conduit::Smc, 3),
+            Some(Ok("hvc")) => (Conduit::Hvc, 2),
+            _ => return false,
+        };
+        if processor::get_current_el() >= conduit.1 {
+            warn!("psci: {} calls would trap to this EL, ignored", if conduit.0 == Conduit::Smc { "SMC" } else { "HVC" });
+            return false;
+        }
+        unsafe {
+            CONDUIT = Some(conduit.0);
+        }
+        true
+    }
+}
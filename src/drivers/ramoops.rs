@@ -0,0 +1,196 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+Persistent log in a RAM region that is not cleared by a warm reset (pstore/ramoops like).
+
+    +---------------+------------------------------------------+
+    | RamoopsHeader | ring of `capacity` bytes                 |
+    +---------------+------------------------------------------+
+
+The ring keeps the newest output: once full, the oldest bytes are overwritten
+and accounted in `lost`. Each boot finding a valid header keeps a copy of its
+content and starts a new log with the next sequence number: recording starts
+as early as possible, the copy is replayed once a console is there to show it.
+Lines are cleaned to the point of coherency as they are written so that the
+content is in DRAM when the reset happens.
+*/
+
+use core::{fmt, ptr, slice};
+use core::arch::asm;
+use core::mem::size_of;
+
+use alloc::format;
+use alloc::string::String;
+
+use crate::log;
+use crate::log::{Level, SinkId};
+use crate::log::TTY;
+
+pub const RAMOOPS : &str = "ramoops";
+
+const RAMOOPS_MAGIC: u32 = 0x474c_4b42; // "BKLG"
+const RAMOOPS_VERSION: u32 = 1;
+const CACHE_LINE: usize = 64;
+
+#[repr(C)]
+struct RamoopsHeader {
+    magic: u32,
+    version: u32,
+    sequence: u64,
+    capacity: u64,
+    write: u64,
+    used: u64,
+    lost: u64,
+}
+
+pub struct RamoopsLogger {
+    header: *mut RamoopsHeader,
+    data: *mut u8,
+    capacity: usize,
+}
+
+// storage for the logger, registered without the heap
+static mut STATIC_RAMOOPS: Option<RamoopsLogger> = None;
+
+// log of the previous boot, until replay_previous
+static mut PREVIOUS: Option<String> = None;
+
+fn clean_range(start: usize, len: usize) {
+    let mut line = start & !(CACHE_LINE - 1);
+    while line < start + len {
+        unsafe {
+            asm!("dc cvac, {}", in(reg) line, options(nostack, preserves_flags));
+        }
+        line += CACHE_LINE;
+    }
+    unsafe {
+        asm!("dsb sy", options(nostack, preserves_flags));
+    }
+}
+
+impl RamoopsLogger {
+
+    fn header(&mut self) -> &mut RamoopsHeader {
+        unsafe { &mut *self.header }
+    }
+
+    fn is_valid(&self) -> bool {
+        let h = unsafe { &*self.header };
+        h.magic == RAMOOPS_MAGIC && h.version == RAMOOPS_VERSION
+            && h.capacity == self.capacity as u64
+            && h.write < h.capacity && h.used <= h.capacity
+    }
+
+    /* the previous content, oldest part first */
+    fn contents(&self) -> (&[u8], &[u8]) {
+        let h = unsafe { &*self.header };
+        let (write, used) = (h.write as usize, h.used as usize);
+        unsafe {
+            if used < self.capacity {
+                (slice::from_raw_parts(self.data, used), &[])
+            }
+            else {
+                (slice::from_raw_parts(self.data.add(write), self.capacity - write),
+                 slice::from_raw_parts(self.data, write))
+            }
+        }
+    }
+
+    fn start(&mut self, sequence: u64) {
+        let capacity = self.capacity as u64;
+        *self.header() = RamoopsHeader {
+            magic: RAMOOPS_MAGIC,
+            version: RAMOOPS_VERSION,
+            sequence,
+            capacity,
+            write: 0,
+            used: 0,
+            lost: 0,
+        };
+        clean_range(self.header as usize, size_of::<RamoopsHeader>());
+    }
+
+    /// Takes over [base, base+size): the log of the previous boot, if any,
+    /// is kept for replay_previous before being replaced.
+    /// Returns the boot sequence number and the sink, None if the region can't
+    /// hold the header and a few lines.
+    pub fn register_static(base: u64, size: u64) -> Option<(u64, SinkId)> {
+        let size = size as usize;
+        if size <= size_of::<RamoopsHeader>() + CACHE_LINE || base as usize & 7 != 0 {
+            return None;
+        }
+        let mut logger = RamoopsLogger {
+            header: base as *mut RamoopsHeader,
+            data: (base as usize + size_of::<RamoopsHeader>()) as *mut u8,
+            capacity: size - size_of::<RamoopsHeader>(),
+        };
+
+        let mut sequence = 0;
+        if logger.is_valid() {
+            let h = unsafe { &*logger.header };
+            sequence = h.sequence + 1;
+            let (older, newer) = logger.contents();
+            let mut previous = format!("---- persistent log of boot #{} ({} bytes, {} lost) ----\n", h.sequence, h.used, h.lost);
+            previous.push_str(&String::from_utf8_lossy(older));
+            previous.push_str(&String::from_utf8_lossy(newer));
+            previous.push_str("\n---- end of persistent log ----\n");
+            unsafe {
+                PREVIOUS = Some(previous);
+            }
+        }
+        logger.start(sequence);
+
+        unsafe {
+            let slot = &mut *ptr::addr_of_mut!(STATIC_RAMOOPS);
+            let sink = log::add_static_sink(slot.insert(logger), Level::Trace)?;
+            Some((sequence, sink))
+        }
+    }
+
+    /// Prints the log of the previous boot on the console only: it is not part of this boot's record.
+    pub fn replay_previous() {
+        if let Some(previous) = unsafe { ptr::replace(ptr::addr_of_mut!(PREVIOUS), None) } {
+            log::write_fmt_to(log::CONSOLE_SINK, format_args!("{}", previous));
+        }
+    }
+}
+
+impl fmt::Write for RamoopsLogger {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let capacity = self.capacity;
+        // only the tail of a string larger than the ring can survive
+        let bytes = s.as_bytes();
+        let skipped = bytes.len().saturating_sub(capacity);
+        let bytes = &bytes[skipped..];
+
+        let h = self.header();
+        let mut write = h.write as usize;
+        let overwritten = (h.used as usize + bytes.len()).saturating_sub(capacity);
+        h.lost += (skipped + overwritten) as u64;
+        h.used = core::cmp::min(h.used as usize + bytes.len(), capacity) as u64;
+
+        let mut remaining = bytes;
+        while !remaining.is_empty() {
+            let n = core::cmp::min(remaining.len(), capacity - write);
+            unsafe {
+                ptr::copy_nonoverlapping(remaining.as_ptr(), self.data.add(write), n);
+            }
+            clean_range(self.data as usize + write, n);
+            write = (write + n) % capacity;
+            remaining = &remaining[n..];
+        }
+        self.header().write = write as u64;
+        clean_range(self.header as usize, size_of::<RamoopsHeader>());
+        Ok(())
+    }
+}
+
+impl TTY for RamoopsLogger {
+    fn get_unprinted(&self) -> String {
+        return String::from("");
+    }
+}
@@ -0,0 +1,168 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+Driver model: each driver declares the compatible strings it handles and
+probes a device tree node into a Device. DRIVERS lists all drivers, adding one
+is a matter of appending it there.
+
+The bind pass walks the enabled nodes once the runtime heap is set up: the
+compatible strings of a node are tried in order, most specific first, against
+the registry and the first driver whose probe succeeds owns the node. Probing
+only reads the node (and at most identification registers). The devices the
+system needs whoever uses them (timers, interrupt controllers...) are started
+by their attach hook right after the probe. The others wait to be claimed, for
+instance as the console named by stdout-path or console=<driver name>, so the
+devices nobody uses are left as the firmware configured them.
+*/
+
+use core::ptr;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use fdt_rs::index::DevTreeIndexNode;
+use fdt_rs::prelude::{FallibleIterator, PropReader};
+
+use crate::dt;
+use crate::dt::DeviceTree;
+use crate::log::Logger;
+use crate::debug;
+
+use crate::drivers::{PL011Driver, NS16550Driver, CadenceDriver, LpuartDriver, MesonDriver, VirtioConsoleDriver, FramebufferDriver, PsciDriver};
+
+/// What a probe records of a node, enough to start the device once claimed.
+pub struct Device {
+    pub compatible: &'static str,
+    pub base: u64,
+}
+
+pub trait Driver: Sync {
+
+    fn name(&self) -> &'static str;
+
+    /// The compatible strings handled by the driver.
+    fn compatible(&self) -> &'static [&'static str];
+
+    /// Checks that `node` describes a usable device without changing its state.
+    fn probe(&self, devt: &DeviceTree, node: &DevTreeIndexNode) -> Option<Device> {
+        let compatible = self.matches(devt, node)?;
+        let base = devt.parse_mmio(node).first()?.base;
+        Some(Device { compatible, base })
+    }
+
+    /// Starts the device during the bind pass, returns true if it is now in use.
+    /// The default leaves it to be claimed.
+    fn attach(&self, _devt: &DeviceTree, _node: &DevTreeIndexNode, _device: &Device) -> bool {
+        false
+    }
+
+    /// Starts the probed device as a console, with the stdout-path options if any.
+    fn start_console(&self, _devt: &DeviceTree, _node: &DevTreeIndexNode, _device: &Device, _options: Option<&str>) -> Option<Box<dyn Logger>> {
+        None
+    }
+
+    /// The most specific compatible string of `node` handled by the driver.
+    fn matches(&self, devt: &DeviceTree, node: &DevTreeIndexNode) -> Option<&'static str> {
+        let prop = devt.get_prop_by_name(node, "compatible")?;
+        let mut candidates = prop.iter_str();
+        while let Ok(Some(s)) = candidates.next() {
+            if let Some(compatible) = self.compatible().iter().find(|c| **c == s) {
+                return Some(compatible);
+            }
+        }
+        None
+    }
+}
+
+static DRIVERS: [&dyn Driver; 8] = [
+    &PL011Driver,
+    &NS16550Driver,
+    &CadenceDriver,
+    &LpuartDriver,
+    &MesonDriver,
+    &VirtioConsoleDriver,
+    &FramebufferDriver,
+    &PsciDriver,
+];
+
+struct Binding {
+    path: String,
+    driver: &'static dyn Driver,
+    device: Device,
+    claimed: bool,
+}
+
+static mut BINDINGS: Vec<Binding> = Vec::new();
+
+fn bindings() -> &'static mut Vec<Binding> {
+    unsafe { &mut *ptr::addr_of_mut!(BINDINGS) }
+}
+
+fn is_enabled(devt: &DeviceTree, node: &DevTreeIndexNode) -> bool {
+    match devt.get_prop_by_name(node, "status") {
+        Some(status) => matches!(status.str(), Ok("okay") | Ok("ok")),
+        None => true,
+    }
+}
+
+fn bind(devt: &DeviceTree, node: &DevTreeIndexNode) -> Option<Binding> {
+    let prop = devt.get_prop_by_name(node, "compatible")?;
+    let mut candidates = prop.iter_str();
+    while let Ok(Some(s)) = candidates.next() {
+        for driver in DRIVERS.iter().filter(|d| d.compatible().contains(&s)) {
+            if let Some(device) = driver.probe(devt, node) {
+                return Some(Binding { path: dt::to_path(node), driver: *driver, device, claimed: false });
+            }
+        }
+    }
+    None
+}
+
+/// Probes every enabled node not bound yet, returns the number of new devices.
+pub fn bind_all(devt: &DeviceTree) -> usize {
+    let mut count = 0;
+    for node in devt.get_nodes() {
+        if !is_enabled(devt, &node) {
+            continue;
+        }
+        let path = dt::to_path(&node);
+        if bindings().iter().any(|b| b.path == path) {
+            continue;
+        }
+        if let Some(mut binding) = bind(devt, &node) {
+            binding.claimed = binding.driver.attach(devt, &node, &binding.device);
+            debug!("{} bound to {}{}", binding.path, binding.driver.name(), if binding.claimed { ", started" } else { "" });
+            bindings().push(binding);
+            count += 1;
+        }
+    }
+    count
+}
+
+fn take_console_if(devt: &DeviceTree, options: Option<&str>, condition: impl Fn(&Binding) -> bool) -> Option<Box<dyn Logger>> {
+    for binding in bindings().iter_mut().filter(|b| !b.claimed && condition(b)) {
+        let node = match devt.get_node_by_path(&binding.path) {
+            Some(node) => node,
+            None => continue,
+        };
+        if let Some(console) = binding.driver.start_console(devt, &node, &binding.device, options) {
+            binding.claimed = true;
+            return Some(console);
+        }
+    }
+    None
+}
+
+/// Starts the device bound to the node at `path` as the console.
+pub fn take_console(devt: &DeviceTree, path: &str, options: Option<&str>) -> Option<Box<dyn Logger>> {
+    take_console_if(devt, options, |b| b.path == path)
+}
+
+/// Starts the first device bound to the driver named `driver` that works as a console.
+pub fn take_console_of(devt: &DeviceTree, driver: &str) -> Option<Box<dyn Logger>> {
+    take_console_if(devt, None, |b| b.driver.name() == driver)
+}
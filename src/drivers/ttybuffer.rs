@@ -5,11 +5,13 @@
 */
 
 use core::fmt;
+use core::ptr;
 use core::slice;
 
 use alloc::{string::{String, ToString}};
 
 use crate::log::TTY;
+use crate::log;
 
 pub struct TTYBuffer {
     pub buffer: *mut u8,
@@ -18,6 +20,26 @@ pub struct TTYBuffer {
     pub count: usize
 }
 
+// storage for the buffer registered without the heap
+static mut STATIC_CONSOLE: TTYBuffer = TTYBuffer::empty();
+
+impl TTYBuffer {
+
+    pub const fn empty() -> TTYBuffer {
+        TTYBuffer { buffer: ptr::null_mut(), capacity: 0, current: 0, count: 0 }
+    }
+
+    /// Makes `buffer` the log target without allocating: usable before heap_init.
+    /// The text is retrieved with log::get_unprinted once a real console is found.
+    pub fn register_static(buffer: *mut u8, capacity: usize) {
+        unsafe {
+            let console = &mut *ptr::addr_of_mut!(STATIC_CONSOLE);
+            *console = TTYBuffer { buffer, capacity, current: 0, count: 0 };
+            log::set_static_target(console);
+        }
+    }
+}
+
 impl fmt::Write for TTYBuffer {
     
     fn write_str(&mut self, s: &str) -> fmt::Result {
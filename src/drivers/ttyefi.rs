@@ -7,7 +7,7 @@ use core::fmt;
 
 use alloc::{string::{String}};
 
-use crate::log::TTY;
+use crate::log::{TTY, ConsoleInput};
 
 use r_efi::efi;
 
@@ -34,4 +34,20 @@ impl TTY for TTYEFI<'_> {
     fn get_unprinted(&self) -> String {
         return String::from("");
     }
+    fn as_input(&mut self) -> Option<&mut dyn ConsoleInput> {
+        Some(self)
+    }
+}
+
+impl ConsoleInput for TTYEFI<'_> {
+    fn try_read(&mut self) -> Option<u8> {
+        let con_in = self.sys_tab.con_in;
+        let mut key = efi::protocols::simple_text_input::InputKey { scan_code: 0, unicode_char: 0 };
+        let status = unsafe { ((*con_in).read_key_stroke)(con_in, &mut key) };
+        // NOT_READY when no key is pending, scan codes (arrows...) have no character
+        if status != efi::Status::SUCCESS || key.unicode_char == 0 {
+            return None;
+        }
+        if key.unicode_char < 0x80 { Some(key.unicode_char as u8) } else { Some(b'?') }
+    }
 }
@@ -0,0 +1,56 @@
+/* 
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+    
+*/
+
+/* Serial line settings shared by the UART drivers. */
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy)]
+pub struct LineConfig {
+    pub baud: u32,
+    pub parity: Parity,
+    pub data_bits: u8,
+    pub stop_bits: u8,
+}
+
+impl LineConfig {
+
+    /// What a UART is set to when neither stdout-path nor current-speed tells.
+    pub const DEFAULT: LineConfig = LineConfig { baud: 115200, parity: Parity::None, data_bits: 8, stop_bits: 1 };
+
+    /// `baud` with no parity, 8 data bits and 1 stop bit, as current-speed implies.
+    pub fn with_baud(baud: u32) -> LineConfig {
+        LineConfig { baud, ..LineConfig::DEFAULT }
+    }
+
+    /// Parses the options of stdout-path or earlycon, "<baud>{<parity>{<bits>{<flow>}}}"
+    /// as in "115200n8"; parity and bits default to n8, flow control is ignored.
+    pub fn parse(options: &str) -> Option<LineConfig> {
+        let digits = options.find(|c: char| !c.is_ascii_digit()).unwrap_or(options.len());
+        let baud = options[..digits].parse::<u32>().ok()?;
+        if baud == 0 {
+            return None;
+        }
+        let mut rest = options[digits..].chars();
+        let parity = match rest.next() {
+            None | Some('n') => Parity::None,
+            Some('o') => Parity::Odd,
+            Some('e') => Parity::Even,
+            _ => return None,
+        };
+        let data_bits = match rest.next() {
+            None => 8,
+            Some(c @ '5'..='8') => c as u8 - b'0',
+            _ => return None,
+        };
+        Some(LineConfig { baud, parity, data_bits, stop_bits: 1 })
+    }
+}
@@ -0,0 +1,283 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+virtio-mmio transport (virtio 1.1 section 4.2), legacy (version 1, QEMU's
+default) and modern (version 2) register layouts.
+
+Virtqueues are split rings allocated on the heap: the runtime is identity
+mapped so their addresses are given to the device as is. Drivers are polled,
+interrupts stay masked.
+
+    page 0: descriptors (16 * size), available ring (6 + 2 * size)
+    page 1: used ring (6 + 8 * size)
+
+The legacy layout (used ring on the next QueueAlign boundary) is also valid
+for version 2 which takes the three addresses separately.
+*/
+
+use core::arch::asm;
+use core::ptr;
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::alloc::alloc_zeroed;
+
+pub const VIRTIO_MMIO : &str = "virtio,mmio";
+
+pub const VIRTIO_DEVICE_CONSOLE: u32 = 3;
+
+const VIRTIO_MAGIC: u32 = 0x7472_6976; // "virt"
+
+// registers
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const GUEST_PAGE_SIZE: u64 = 0x028;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_ALIGN: u64 = 0x03c;
+const QUEUE_PFN: u64 = 0x040;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+
+// status bits
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// descriptor flags
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const PAGE_SIZE: usize = 4096;
+// one page of descriptors and available ring, one page of used ring
+const QUEUE_PAGES: usize = 2;
+// keeps descriptors and available ring within the first page
+const MAX_QUEUE_SIZE: u32 = 128;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/* DMA memory is seen by the device in DRAM order, not through our caches'
+   view of ordering */
+fn barrier() {
+    unsafe {
+        asm!("dsb sy", options(nostack, preserves_flags));
+    }
+}
+
+pub struct VirtioMmio {
+    pub base: u64,
+    pub version: u32,
+}
+
+impl VirtioMmio {
+
+    /// Returns the transport if a device sits behind `base`: QEMU populates
+    /// its virtio-mmio slots with device ID 0 when nothing is plugged.
+    pub fn probe(base: u64) -> Option<VirtioMmio> {
+        let transport = VirtioMmio { base, version: 0 };
+        if transport.read(MAGIC_VALUE) != VIRTIO_MAGIC {
+            return None;
+        }
+        let version = transport.read(VERSION);
+        if version != 1 && version != 2 {
+            return None;
+        }
+        if transport.read(DEVICE_ID) == 0 {
+            return None;
+        }
+        Some(VirtioMmio { base, version })
+    }
+
+    fn reg(&self, offset: u64) -> &AtomicU32 {
+        unsafe { &*((self.base + offset) as *const AtomicU32) }
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        self.reg(offset).load(Ordering::Acquire)
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        self.reg(offset).store(value, Ordering::Release);
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.read(DEVICE_ID)
+    }
+
+    /// Resets the device and negotiates `features` (the subset the device
+    /// offers is accepted). Returns false if the device refuses.
+    pub fn init(&self, features: u64) -> bool {
+        self.write(STATUS, 0);
+        self.write(STATUS, STATUS_ACKNOWLEDGE);
+        self.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let mut offered = self.read(DEVICE_FEATURES) as u64;
+        self.write(DEVICE_FEATURES_SEL, 1);
+        offered |= (self.read(DEVICE_FEATURES) as u64) << 32;
+
+        let mut features = features & offered;
+        if self.version == 2 {
+            if offered & VIRTIO_F_VERSION_1 == 0 {
+                self.write(STATUS, STATUS_FAILED);
+                return false;
+            }
+            features |= VIRTIO_F_VERSION_1;
+        }
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (features >> 32) as u32);
+
+        if self.version == 1 {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            return true;
+        }
+        self.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if self.read(STATUS) & STATUS_FEATURES_OK == 0 {
+            self.write(STATUS, STATUS_FAILED);
+            return false;
+        }
+        true
+    }
+
+    /// Allocates and publishes queue `index` with at most `size` entries.
+    pub fn setup_queue(&self, index: u32, size: u16) -> Option<Virtqueue> {
+        self.write(QUEUE_SEL, index);
+        let max = self.read(QUEUE_NUM_MAX);
+        if max == 0 {
+            return None;
+        }
+        let size = core::cmp::min(core::cmp::min(max, size as u32), MAX_QUEUE_SIZE) as u16;
+        let layout = Layout::from_size_align(QUEUE_PAGES * PAGE_SIZE, PAGE_SIZE).ok()?;
+        let memory = unsafe { alloc_zeroed(layout) } as u64;
+        if memory == 0 {
+            return None;
+        }
+        let queue = Virtqueue {
+            index,
+            size,
+            desc: memory as *mut Descriptor,
+            avail: (memory + 16 * size as u64) as *mut u16,
+            used: (memory + PAGE_SIZE as u64) as *mut u16,
+            avail_idx: 0,
+            last_used: 0,
+        };
+
+        self.write(QUEUE_NUM, size as u32);
+        if self.version == 1 {
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, (memory / PAGE_SIZE as u64) as u32);
+        }
+        else {
+            let (desc, avail, used) = (queue.desc as u64, queue.avail as u64, queue.used as u64);
+            self.write(QUEUE_DESC_LOW, desc as u32);
+            self.write(QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(QUEUE_DRIVER_LOW, avail as u32);
+            self.write(QUEUE_DRIVER_HIGH, (avail >> 32) as u32);
+            self.write(QUEUE_DEVICE_LOW, used as u32);
+            self.write(QUEUE_DEVICE_HIGH, (used >> 32) as u32);
+            self.write(QUEUE_READY, 1);
+        }
+        Some(queue)
+    }
+
+    /// The device is live once the queues are set up.
+    pub fn driver_ok(&self) {
+        let status = self.read(STATUS);
+        self.write(STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    pub fn notify(&self, queue: &Virtqueue) {
+        barrier();
+        self.write(QUEUE_NOTIFY, queue.index);
+    }
+
+    /// Acknowledges whatever the device signalled, as nothing is interrupt driven.
+    pub fn ack_interrupts(&self) {
+        let status = self.read(INTERRUPT_STATUS);
+        if status != 0 {
+            self.write(INTERRUPT_ACK, status);
+        }
+    }
+}
+
+/// A split virtqueue, descriptors are managed by the driver.
+pub struct Virtqueue {
+    index: u32,
+    pub size: u16,
+    desc: *mut Descriptor,
+    avail: *mut u16,
+    used: *mut u16,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+
+    pub fn set_descriptor(&mut self, id: u16, addr: u64, len: u32, flags: u16) {
+        unsafe {
+            ptr::write_volatile(self.desc.add(id as usize), Descriptor { addr, len, flags, next: 0 });
+        }
+    }
+
+    /// Hands descriptor `id` to the device, the caller then notifies.
+    pub fn push(&mut self, id: u16) {
+        unsafe {
+            // avail: flags, idx, ring[size]
+            ptr::write_volatile(self.avail.add(2 + (self.avail_idx % self.size) as usize), id);
+            barrier();
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            ptr::write_volatile(self.avail.add(1), self.avail_idx);
+        }
+    }
+
+    /// The next descriptor the device is done with and the length it wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        unsafe {
+            // used: flags, idx, ring[size] of (id, len)
+            if ptr::read_volatile(self.used.add(1)) == self.last_used {
+                return None;
+            }
+            barrier();
+            let ring = self.used.add(2) as *const UsedElement;
+            let element = ptr::read_volatile(ring.add((self.last_used % self.size) as usize));
+            self.last_used = self.last_used.wrapping_add(1);
+            Some((element.id as u16, element.len))
+        }
+    }
+}
@@ -0,0 +1,171 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+virtio console (device ID 3) on the virtio-mmio transport, port 0 only:
+queue 0 receives, queue 1 transmits.
+
+Output is copied in a bounce buffer sent synchronously, one descriptor at a
+time: the log then reaches the host in large chunks instead of a byte per trap.
+Input uses a few device writable buffers, requeued once read.
+*/
+
+use core::fmt;
+use alloc::string::String;
+use alloc::boxed::Box;
+use alloc::vec;
+
+use crate::dt::DeviceTree;
+use crate::drivers::virtio::{VirtioMmio, Virtqueue, VIRTIO_MMIO, VIRTIO_DEVICE_CONSOLE, VIRTQ_DESC_F_WRITE};
+use crate::log::{TTY, ConsoleInput};
+use crate::log::Logger;
+use crate::drivers::registry::{Driver, Device};
+
+use fdt_rs::index::DevTreeIndexNode;
+
+const RECEIVEQ: u32 = 0;
+const TRANSMITQ: u32 = 1;
+
+const RX_BUFFERS: u16 = 4;
+const RX_CHUNK: usize = 64;
+const TX_CHUNK: usize = 512;
+
+pub struct VirtioConsole {
+    #[allow(dead_code)]
+    pub compatible: &'static str,
+    transport: VirtioMmio,
+    rx: Virtqueue,
+    tx: Virtqueue,
+    rx_buffers: Box<[u8]>,
+    tx_buffer: Box<[u8]>,
+    tx_length: usize,
+    // buffer being read: descriptor, bytes received, bytes consumed
+    rx_current: Option<(u16, usize, usize)>,
+}
+
+impl VirtioConsole {
+
+    /// None if there is no console device behind `mmio_base`.
+    pub fn new(compatible: &'static str, mmio_base: u64) -> Option<Box<dyn Logger>> {
+        let transport = VirtioMmio::probe(mmio_base)?;
+        if transport.device_id() != VIRTIO_DEVICE_CONSOLE || !transport.init(0) {
+            return None;
+        }
+        let mut rx = transport.setup_queue(RECEIVEQ, RX_BUFFERS)?;
+        let tx = transport.setup_queue(TRANSMITQ, 1)?;
+        let rx_buffers = vec![0u8; rx.size as usize * RX_CHUNK].into_boxed_slice();
+        for id in 0..rx.size {
+            let address = rx_buffers.as_ptr() as u64 + id as u64 * RX_CHUNK as u64;
+            rx.set_descriptor(id, address, RX_CHUNK as u32, VIRTQ_DESC_F_WRITE);
+            rx.push(id);
+        }
+        transport.driver_ok();
+        transport.notify(&rx);
+
+        Some(Box::new(VirtioConsole {
+            compatible,
+            transport,
+            rx,
+            tx,
+            rx_buffers,
+            tx_buffer: vec![0u8; TX_CHUNK].into_boxed_slice(),
+            tx_length: 0,
+            rx_current: None,
+        }))
+    }
+
+    /* sends the bounce buffer and waits for the device to consume it */
+    fn flush(&mut self) {
+        if self.tx_length == 0 {
+            return;
+        }
+        self.tx.set_descriptor(0, self.tx_buffer.as_ptr() as u64, self.tx_length as u32, 0);
+        self.tx.push(0);
+        self.transport.notify(&self.tx);
+        while self.tx.pop_used().is_none() {
+            core::hint::spin_loop();
+        }
+        self.transport.ack_interrupts();
+        self.tx_length = 0;
+    }
+
+    fn putc(&mut self, c: u8) {
+        if self.tx_length == TX_CHUNK {
+            self.flush();
+        }
+        self.tx_buffer[self.tx_length] = c;
+        self.tx_length += 1;
+    }
+}
+
+pub const VIRTIO_CONSOLE_DRIVER : &str = "virtio-console";
+
+/* every virtio,mmio slot is probed, only the console devices are kept: the
+device ID is read without resetting the device */
+pub struct VirtioConsoleDriver;
+
+impl Driver for VirtioConsoleDriver {
+    fn name(&self) -> &'static str {
+        VIRTIO_CONSOLE_DRIVER
+    }
+    fn compatible(&self) -> &'static [&'static str] {
+        &[VIRTIO_MMIO]
+    }
+    fn probe(&self, devt: &DeviceTree, node: &DevTreeIndexNode) -> Option<Device> {
+        let base = devt.parse_mmio(node).first()?.base;
+        if VirtioMmio::probe(base)?.device_id() != VIRTIO_DEVICE_CONSOLE {
+            return None;
+        }
+        Some(Device { compatible: VIRTIO_MMIO, base })
+    }
+    fn start_console(&self, _devt: &DeviceTree, _node: &DevTreeIndexNode, device: &Device, _options: Option<&str>) -> Option<Box<dyn Logger>> {
+        VirtioConsole::new(device.compatible, device.base)
+    }
+}
+
+impl fmt::Write for VirtioConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            if c == b'\n' {
+                self.putc(b'\r');
+            }
+            self.putc(c);
+        }
+        self.flush();
+        Ok(())
+    }
+}
+
+impl TTY for VirtioConsole {
+    fn get_unprinted(&self) -> String {
+        return String::from("");
+    }
+    fn as_input(&mut self) -> Option<&mut dyn ConsoleInput> {
+        Some(self)
+    }
+}
+
+impl ConsoleInput for VirtioConsole {
+    fn try_read(&mut self) -> Option<u8> {
+        if self.rx_current.is_none() {
+            let (id, length) = self.rx.pop_used()?;
+            self.transport.ack_interrupts();
+            self.rx_current = Some((id, core::cmp::min(length as usize, RX_CHUNK), 0));
+        }
+        let (id, length, consumed) = self.rx_current?;
+        let c = (consumed < length).then(|| self.rx_buffers[id as usize * RX_CHUNK + consumed]);
+        if consumed + 1 >= length {
+            // the buffer goes back to the device, its descriptor is unchanged
+            self.rx_current = None;
+            self.rx.push(id);
+            self.transport.notify(&self.rx);
+        }
+        else {
+            self.rx_current = Some((id, length, consumed + 1));
+        }
+        c
+    }
+}
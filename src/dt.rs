@@ -33,6 +33,7 @@ pub struct DeviceTree<'a> {
     pub device_type: &'a str 
 }
 
+#[derive(Clone, Copy)]
 pub struct Region {
     pub base: u64,
     pub size: u64
@@ -220,6 +221,115 @@ impl<'a> DeviceTree<'a> {
         return finder.next();
     }
 
+    /* all memory nodes may be named memory or memory@<unit address> */
+    pub fn get_memory(&self) -> Vec<Region> {
+        let mut result: Vec<Region> = Vec::new();
+        for node in self.index.nodes() {
+            let name = node.name().unwrap();
+            if !name.eq("memory") && !name.starts_with("memory@") {
+                continue;
+            }
+            if let Some(reg_prop) = self.get_prop_by_name(&node, "reg") {
+                result.append(&mut read_two_items(reg_prop, self.acells, self.scells));
+            }
+        }
+        return result;
+    }
+
+    /* entries of the /memreserve/ block in the FDT header, terminated by a 0/0 entry */
+    pub fn get_memreserve(&self) -> Vec<Region> {
+        let mut result: Vec<Region> = Vec::new();
+        let buffer = self.devtree.buf();
+        let mut offset = self.devtree.off_mem_rsvmap();
+        while offset + 2 * size_of::<u64>() <= buffer.len() {
+            #[allow(clippy::cast_ptr_alignment)]
+            let (base, size) = unsafe {
+                let entry = buffer.as_ptr().add(offset) as *const u64;
+                (u64::from_be(read_unaligned(entry)), u64::from_be(read_unaligned(entry.add(1))))
+            };
+            if base == 0 && size == 0 {
+                break;
+            }
+            result.push(Region { base, size });
+            offset += 2 * size_of::<u64>();
+        }
+        return result;
+    }
+
+    /* static carve-outs described as /reserved-memory children, dynamic ones have no reg */
+    pub fn get_reserved_memory(&self) -> Vec<Region> {
+        let mut result: Vec<Region> = Vec::new();
+        if let Some(reserved) = self.get_node_by_path("/reserved-memory") {
+            for child in reserved.children() {
+                if self.get_prop_by_name(&child, "reg").is_some() {
+                    result.append(&mut self.parse_mmio(&child));
+                }
+            }
+        }
+        return result;
+    }
+
+    /// First /reserved-memory carve-out with the given compatible, e.g. "ramoops".
+    pub fn get_reserved_memory_by_compatible(&self, compatible: &str) -> Option<Region> {
+        let reserved = self.get_node_by_path("/reserved-memory")?;
+        for child in reserved.children() {
+            if self.is_compatible(&child, compatible) && self.get_prop_by_name(&child, "reg").is_some() {
+                return self.parse_mmio(&child).first().copied();
+            }
+        }
+        None
+    }
+
+    /// Optional u32 property: None when missing or too short, never panics.
+    pub fn get_u32(&self, node: &DevTreeIndexNode, name: &str) -> Option<u32> {
+        self.get_prop_by_name(node, name)?.u32(0).ok()
+    }
+
+    pub fn get_node_by_phandle(&self, phandle: u32) -> Option<DevTreeIndexNode<'_, '_, '_>> {
+        self.index.nodes().find(|node| {
+            self.get_u32(node, "phandle").or_else(|| self.get_u32(node, "linux,phandle")) == Some(phandle)
+        })
+    }
+
+    /* clocks = <&provider args...>, the number of args is the #clock-cells of each provider */
+    fn get_clock_provider(&self, node: &DevTreeIndexNode, index: usize) -> Option<DevTreeIndexNode<'_, '_, '_>> {
+        let clocks = self.get_prop_by_name(node, "clocks")?;
+        let cells = clocks.length() / size_of::<u32>();
+        let mut cell = 0;
+        let mut i = 0;
+        while cell < cells {
+            let provider = self.get_node_by_phandle(clocks.u32(cell).ok()?)?;
+            if i == index {
+                return Some(provider);
+            }
+            cell += 1 + self.get_u32(&provider, "#clock-cells").unwrap_or(0) as usize;
+            i += 1;
+        }
+        None
+    }
+
+    /// Frequency of the clock named `clock_name` (first clock if the name is not
+    /// found) from the `clocks` provider, or the node's own `clock-frequency`.
+    /// Only fixed clocks are understood.
+    pub fn get_clock_frequency(&self, node: &DevTreeIndexNode, clock_name: &str) -> Option<u32> {
+        if let Some(frequency) = self.get_u32(node, "clock-frequency") {
+            return Some(frequency);
+        }
+        let index = match self.get_prop_by_name(node, "clock-names") {
+            Some(names) => names.iter_str().position(|name| Ok(name == clock_name)).ok().flatten().unwrap_or(0),
+            None => 0,
+        };
+        let provider = self.get_clock_provider(node, index)?;
+        self.get_u32(&provider, "clock-frequency")
+    }
+
+    pub fn is_compatible(&self, node: &DevTreeIndexNode, compatible: &str) -> bool {
+        match self.get_prop_by_name(node, "compatible") {
+            Some(prop) => prop.iter_str().any(|s| Ok(s == compatible)).unwrap_or(false),
+            None => false
+        }
+    }
+
     #[allow(dead_code)]
     pub fn get_prop_by_name<'i, 'dt>(&self, node: &DevTreeIndexNode<'a, 'i, 'dt>, name: &str) -> Option<DevTreeIndexProp<'a, 'i, 'dt>> {
         let mut finder = node.props().filter (|x| x.name().unwrap().eq(name));
@@ -0,0 +1,245 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+Early console: a polled UART usable before the heap and before the device tree
+is parsed, selected with a Linux like earlycon specification:
+
+    pl011,<addr>                    PL011 / SBSA UART
+    uart8250,mmio,<addr>            8250/16550 with 8 bit registers
+    uart8250,mmio32,<addr>          8250/16550 with 32 bit registers 4 bytes apart
+    <board>                         one of the PROFILES below
+
+Options after the address (",115200n8") are accepted and ignored: the firmware
+already programmed the UART.
+The build time choice comes from the BAREKIT_EARLYCON environment variable
+(qemu when only the early_print feature is set) and the earlycon= word of the
+command line takes over once known. The early console is a log sink and is
+removed when the real console driver is installed, unless keep_bootcon is given.
+Under EFI, ConOut replaces it as soon as the platform sets its boot console.
+*/
+
+use core::{fmt, ptr};
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+use core::hint;
+
+use alloc::string::String;
+
+use crate::cmdline::parse_u64;
+use crate::log;
+use crate::log::{Level, SinkId, TTY};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum EarlyConKind {
+    Pl011,
+    Uart8250,
+}
+
+#[derive(Clone, Copy)]
+pub struct EarlyCon {
+    pub kind: EarlyConKind,
+    pub base: u64,
+    pub reg_io: u32,
+    pub reg_shift: u32,
+}
+
+//SETUP: boards known by name, look for SETUP to know all places to change values
+const PROFILES: [(&str, &str); 6] = [
+    ("qemu", "pl011,0x09000000"),
+    ("kvmtool", "uart8250,mmio,0x01000000"),
+    ("rpi4", "uart8250,mmio32,0xfe215040"),
+    ("honeycomb", "pl011,0x021c0000"),
+    ("synquacer", "pl011,0x2a400000"),
+    ("macchiatobin", "uart8250,mmio32,0xf0512000"),
+];
+
+#[cfg(feature = "early_print")]
+const BUILD_EARLYCON: Option<&str> = match option_env!("BAREKIT_EARLYCON") {
+    Some(spec) => Some(spec),
+    None => Some("qemu"),
+};
+#[cfg(not(feature = "early_print"))]
+const BUILD_EARLYCON: Option<&str> = option_env!("BAREKIT_EARLYCON");
+
+// PL011 registers
+const PL011_FR: u64 = 0x18;
+const PL011_FR_TXFF: u32 = 1 << 5;
+// 8250 registers, in reg_shift units
+const UART8250_LSR: u64 = 5;
+const UART8250_LSR_THRE: u32 = 1 << 5;
+
+impl EarlyCon {
+
+    /// Parses a specification or a board name, None if it is not understood.
+    pub fn parse(spec: &str) -> Option<EarlyCon> {
+        let spec = PROFILES.iter().find(|(name, _)| *name == spec).map_or(spec, |(_, s)| *s);
+        let mut fields = spec.split(',');
+        match fields.next()? {
+            "pl011" => {
+                let mut field = fields.next()?;
+                if field == "mmio32" {
+                    field = fields.next()?;
+                }
+                Some(EarlyCon { kind: EarlyConKind::Pl011, base: parse_u64(field)?, reg_io: 4, reg_shift: 0 })
+            }
+            "uart8250" | "uart" | "ns16550a" => {
+                let (reg_io, reg_shift) = match fields.next()? {
+                    "mmio" => (1, 0),
+                    "mmio32" => (4, 2),
+                    _ => return None,
+                };
+                Some(EarlyCon { kind: EarlyConKind::Uart8250, base: parse_u64(fields.next()?)?, reg_io, reg_shift })
+            }
+            _ => None,
+        }
+    }
+
+    fn wait_tx_ready(&self) {
+        loop {
+            let ready = unsafe {
+                match self.kind {
+                    EarlyConKind::Pl011 => {
+                        let fr = &*((self.base + PL011_FR) as *const AtomicU32);
+                        fr.load(Ordering::Acquire) & PL011_FR_TXFF == 0
+                    }
+                    EarlyConKind::Uart8250 => {
+                        let lsr = self.base + (UART8250_LSR << self.reg_shift);
+                        let value = if self.reg_io == 4 {
+                            (*(lsr as *const AtomicU32)).load(Ordering::Acquire)
+                        } else {
+                            (*(lsr as *const AtomicU8)).load(Ordering::Acquire) as u32
+                        };
+                        value & UART8250_LSR_THRE != 0
+                    }
+                }
+            };
+            if ready {
+                return;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    fn put_raw(&self, c: u8) {
+        self.wait_tx_ready();
+        unsafe {
+            if self.kind == EarlyConKind::Pl011 || self.reg_io == 4 {
+                (*(self.base as *const AtomicU32)).store(c as u32, Ordering::Release);
+            } else {
+                (*(self.base as *const AtomicU8)).store(c, Ordering::Release);
+            }
+        }
+    }
+
+    pub fn putc(&self, c: u8) {
+        if c == b'\n' {
+            self.put_raw(b'\r');
+        }
+        self.put_raw(c);
+    }
+}
+
+static mut SELECTED: Option<EarlyCon> = None;
+static mut BUILD_DEFAULT_CHECKED: bool = false;
+static mut SINK: Option<SinkId> = None;
+
+/// The early console in use, the build time one until earlycon= is seen.
+pub fn current() -> Option<EarlyCon> {
+    unsafe {
+        if !BUILD_DEFAULT_CHECKED {
+            BUILD_DEFAULT_CHECKED = true;
+            let selected = SELECTED;
+            if selected.is_none() {
+                SELECTED = BUILD_EARLYCON.and_then(EarlyCon::parse);
+            }
+        }
+        SELECTED
+    }
+}
+
+/// Selects the early console from a specification, returns false if not understood.
+pub fn select(spec: &str) -> bool {
+    match EarlyCon::parse(spec) {
+        Some(earlycon) => {
+            unsafe {
+                BUILD_DEFAULT_CHECKED = true;
+                SELECTED = Some(earlycon);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Raw output for early_prints!, dropped when there is no early console.
+#[allow(dead_code)]
+pub fn putc(c: u8) {
+    if let Some(earlycon) = current() {
+        earlycon.putc(c);
+    }
+}
+
+pub struct EarlyConsole {
+}
+
+// storage for the sink, registered without the heap
+static mut STATIC_CONSOLE: EarlyConsole = EarlyConsole {};
+
+impl fmt::Write for EarlyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(earlycon) = current() {
+            for c in s.bytes() {
+                earlycon.putc(c);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TTY for EarlyConsole {
+    fn get_unprinted(&self) -> String {
+        return String::from("");
+    }
+}
+
+/// Mirrors the log on the early console if one is selected: println! and
+/// friends then work with full formatting, heap or not.
+pub fn register() {
+    unsafe {
+        let sink = SINK;
+        if sink.is_some() || current().is_none() {
+            return;
+        }
+        SINK = log::add_static_sink(&mut *ptr::addr_of_mut!(STATIC_CONSOLE), Level::Trace);
+    }
+}
+
+/// Removes the early console sink, returns true if it was active.
+pub fn unregister() -> bool {
+    unsafe {
+        match ptr::replace(ptr::addr_of_mut!(SINK), None) {
+            Some(id) => {
+                log::remove_sink(id);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Applies earlycon= from the command line: a bare "earlycon" keeps the build time choice.
+pub fn setup_from_cmdline() {
+    if let Some(spec) = crate::cmdline::get_value("earlycon") {
+        if !select(spec) {
+            crate::warn!("earlycon={} not understood", spec);
+            return;
+        }
+    }
+    else if !crate::cmdline::has_flag("earlycon") {
+        return;
+    }
+    register();
+}
@@ -0,0 +1,278 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+Fatal exception reports: the syndrome is decoded (exception class and the ISS
+fields that matter for it), then the saved ExceptionFrame is dumped with the
+fault address and a backtrace starting at the faulting instruction.
+
+FAR_ELx is read in the handler: nothing between the vector and the report can
+fault, so it is still the one of the exception. It is only printed for the
+classes that set it.
+*/
+
+use core::arch::asm;
+
+use crate::backtrace;
+use crate::processor;
+use crate::processor::ExceptionFrame;
+use crate::symbols;
+use crate::{print, println};
+
+// exception classes, ESR_ELx.EC
+const EC_UNKNOWN: u64 = 0x00;
+const EC_SVC64: u64 = 0x15;
+const EC_HVC64: u64 = 0x16;
+const EC_SMC64: u64 = 0x17;
+const EC_SYSREG: u64 = 0x18;
+const EC_IABT_LOWER: u64 = 0x20;
+const EC_IABT_SAME: u64 = 0x21;
+const EC_PC_ALIGNMENT: u64 = 0x22;
+const EC_DABT_LOWER: u64 = 0x24;
+const EC_DABT_SAME: u64 = 0x25;
+const EC_SERROR: u64 = 0x2f;
+const EC_WATCHPOINT_LOWER: u64 = 0x34;
+const EC_WATCHPOINT_SAME: u64 = 0x35;
+const EC_BRK: u64 = 0x3c;
+
+// ISS bits of the aborts and SErrors
+const ISS_ISV: u64 = 1 << 24;
+const ISS_IDS: u64 = 1 << 24;
+const ISS_SF: u64 = 1 << 15;
+const ISS_FNV: u64 = 1 << 10;
+const ISS_EA: u64 = 1 << 9;
+const ISS_CM: u64 = 1 << 8;
+const ISS_S1PTW: u64 = 1 << 7;
+const ISS_WNR: u64 = 1 << 6;
+
+pub fn ec_name(ec: u64) -> &'static str {
+    match ec {
+        EC_UNKNOWN => "unknown reason",
+        0x01 => "trapped WFI/WFE",
+        0x03 => "trapped MCR/MRC (cp15)",
+        0x04 => "trapped MCRR/MRRC (cp15)",
+        0x05 => "trapped MCR/MRC (cp14)",
+        0x06 => "trapped LDC/STC",
+        0x07 => "trapped SVE/SIMD/FP access",
+        0x0a => "trapped LD64B/ST64B",
+        0x0c => "trapped MRRC (cp14)",
+        0x0d => "branch target exception",
+        0x0e => "illegal execution state",
+        0x11 => "SVC (AArch32)",
+        0x12 => "HVC (AArch32)",
+        0x13 => "SMC (AArch32)",
+        EC_SVC64 => "SVC",
+        EC_HVC64 => "HVC",
+        EC_SMC64 => "SMC",
+        EC_SYSREG => "trapped MSR/MRS or system instruction",
+        0x19 => "trapped SVE access",
+        0x1a => "trapped ERET",
+        0x1c => "pointer authentication failure",
+        EC_IABT_LOWER => "instruction abort from a lower EL",
+        EC_IABT_SAME => "instruction abort",
+        EC_PC_ALIGNMENT => "PC alignment fault",
+        EC_DABT_LOWER => "data abort from a lower EL",
+        EC_DABT_SAME => "data abort",
+        0x26 => "SP alignment fault",
+        0x28 => "trapped FP exception (AArch32)",
+        0x2c => "trapped FP exception",
+        EC_SERROR => "SError",
+        0x30 => "breakpoint from a lower EL",
+        0x31 => "breakpoint",
+        0x32 => "software step from a lower EL",
+        0x33 => "software step",
+        EC_WATCHPOINT_LOWER => "watchpoint from a lower EL",
+        EC_WATCHPOINT_SAME => "watchpoint",
+        0x38 => "BKPT (AArch32)",
+        EC_BRK => "BRK",
+        _ => "reserved",
+    }
+}
+
+/* DFSC/IFSC: the low two bits are the translation level for most of the codes */
+fn print_fault_status(status: u64) {
+    let level = status & 3;
+    match status {
+        0b000000..=0b000011 => print!("address size fault, level {}", level),
+        0b000100..=0b000111 => print!("translation fault, level {}", level),
+        0b001000..=0b001011 => print!("access flag fault, level {}", level),
+        0b001100..=0b001111 => print!("permission fault, level {}", level),
+        0b010000 => print!("synchronous external abort"),
+        0b010001 => print!("tag check fault"),
+        0b010100..=0b010111 => print!("synchronous external abort on table walk, level {}", level),
+        0b011000 => print!("parity/ECC error"),
+        0b011100..=0b011111 => print!("parity/ECC error on table walk, level {}", level),
+        0b100001 => print!("alignment fault"),
+        0b110000 => print!("TLB conflict abort"),
+        0b110001 => print!("unsupported atomic hardware update"),
+        _ => print!("fault status {:#04x}", status),
+    }
+}
+
+/* the fields common to instruction and data aborts */
+fn print_abort_flags(iss: u64) {
+    if iss & ISS_EA != 0 {
+        print!(", external");
+    }
+    if iss & ISS_S1PTW != 0 {
+        print!(", on stage 1 table walk");
+    }
+    if iss & ISS_FNV != 0 {
+        print!(", FAR not valid");
+    }
+}
+
+fn print_data_abort(iss: u64) {
+    print_fault_status(iss & 0x3f);
+    print!(", {}", if iss & ISS_WNR != 0 { "write" } else { "read" });
+    if iss & ISS_CM != 0 {
+        print!(" (cache maintenance)");
+    }
+    // access size and register are only valid with ISV
+    if iss & ISS_ISV != 0 {
+        let size = 1 << ((iss >> 22) & 3);
+        let srt = (iss >> 16) & 0x1f;
+        let register = if iss & ISS_SF != 0 { 'x' } else { 'w' };
+        print!(", {} bytes, {}{}", size, register, srt);
+    }
+    print_abort_flags(iss);
+    println!();
+}
+
+fn print_sysreg_trap(iss: u64) {
+    let op0 = (iss >> 20) & 3;
+    let op2 = (iss >> 17) & 7;
+    let op1 = (iss >> 14) & 7;
+    let crn = (iss >> 10) & 0xf;
+    let rt = (iss >> 5) & 0x1f;
+    let crm = (iss >> 1) & 0xf;
+    if iss & 1 != 0 {
+        println!("mrs x{}, S{}_{}_C{}_C{}_{}", rt, op0, op1, crn, crm, op2);
+    }
+    else {
+        println!("msr S{}_{}_C{}_C{}_{}, x{}", op0, op1, crn, crm, op2, rt);
+    }
+}
+
+fn print_serror(iss: u64) {
+    // IDS: the syndrome is implementation defined
+    if iss & ISS_IDS != 0 {
+        println!("implementation defined syndrome {:#x}", iss & 0xff_ffff);
+        return;
+    }
+    let severity = match (iss >> 10) & 7 {
+        0b000 => "uncontainable",
+        0b001 => "unrecoverable",
+        0b010 => "restartable",
+        0b011 => "recoverable",
+        0b110 => "corrected",
+        _ => "unknown severity",
+    };
+    let kind = if iss & 0x3f == 0b010001 { "asynchronous SError" } else { "uncategorized" };
+    print!("{}, {}", kind, severity);
+    if iss & ISS_EA != 0 {
+        print!(", external");
+    }
+    println!();
+}
+
+fn print_iss(ec: u64, iss: u64) {
+    match ec {
+        EC_DABT_LOWER | EC_DABT_SAME => print_data_abort(iss),
+        EC_IABT_LOWER | EC_IABT_SAME => {
+            print_fault_status(iss & 0x3f);
+            print_abort_flags(iss);
+            println!();
+        }
+        EC_SVC64 | EC_HVC64 | EC_SMC64 | EC_BRK => println!("immediate {:#x}", iss & 0xffff),
+        EC_SYSREG => print_sysreg_trap(iss),
+        EC_SERROR => print_serror(iss),
+        _ => println!("ISS {:#x}", iss),
+    }
+}
+
+fn far_is_valid(ec: u64, iss: u64) -> bool {
+    match ec {
+        EC_IABT_LOWER | EC_IABT_SAME | EC_DABT_LOWER | EC_DABT_SAME => iss & ISS_FNV == 0,
+        EC_PC_ALIGNMENT | EC_WATCHPOINT_LOWER | EC_WATCHPOINT_SAME => true,
+        _ => false,
+    }
+}
+
+fn mode_name(spsr: u64) -> &'static str {
+    if spsr & 0x10 != 0 {
+        return "AArch32";
+    }
+    match spsr & 0xf {
+        0b0000 => "EL0t",
+        0b0100 => "EL1t",
+        0b0101 => "EL1h",
+        0b1000 => "EL2t",
+        0b1001 => "EL2h",
+        0b1100 => "EL3t",
+        0b1101 => "EL3h",
+        _ => "invalid mode",
+    }
+}
+
+/* the frame saves SP_ELx of the handler: it is the interrupted SP only for ELxh of the same EL */
+fn interrupted_sp(ef: &ExceptionFrame) -> u64 {
+    let mode = ef.spsr & 0x1f;
+    let value: u64;
+    if mode & 0x10 != 0 || (mode >> 2) as u8 == processor::get_current_el() && mode & 1 != 0 {
+        return ef.gp_regs.sp;
+    }
+    unsafe {
+        match mode {
+            0b0000 | 0b0100 | 0b1000 | 0b1100 => asm!("mrs {}, SP_EL0", out(reg) value),
+            0b0101 => asm!("mrs {}, SP_EL1", out(reg) value),
+            0b1001 => asm!("mrs {}, SP_EL2", out(reg) value),
+            _ => return ef.gp_regs.sp,
+        }
+    }
+    value
+}
+
+fn print_registers(ef: &ExceptionFrame) {
+    for (i, value) in ef.gp_regs.x.iter().enumerate() {
+        print!("x{:<2} {:#018x}", i, value);
+        print!("{}", if i % 4 == 3 { "\n" } else { "  " });
+    }
+    println!("sp  {:#018x}", interrupted_sp(ef));
+}
+
+/* everything but the syndrome, then parks the CPU */
+fn dump_and_park(ef: &ExceptionFrame) -> ! {
+    print!("ELR  {:#018x}", ef.elr);
+    symbols::print_symbol(ef.elr);
+    println!();
+    println!("SPSR {:#018x} ({}, DAIF {:#x}, NZCV {:#x})", ef.spsr, mode_name(ef.spsr), (ef.spsr >> 6) & 0xf, (ef.spsr >> 28) & 0xf);
+    print_registers(ef);
+    backtrace::print_from_exception(&ef.stack_frame);
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Reports an exception that can't be handled and parks the CPU: a panic would
+/// only add its own frames to the backtrace.
+pub fn fatal(name: &str, ef: &ExceptionFrame) -> ! {
+    let ec = (ef.esr >> 26) & 0x3f;
+    let iss = ef.esr & 0x1ff_ffff;
+    println!("*** {}: {} (EC {:#04x}, ESR {:#x})", name, ec_name(ec), ec, ef.esr);
+    print!("    ");
+    print_iss(ec, iss);
+    if far_is_valid(ec, iss) {
+        println!("FAR  {:#018x}", processor::get_far());
+    }
+    dump_and_park(ef);
+}
+
+/// Same as fatal for IRQ and FIQ, which leave ESR and FAR unchanged.
+pub fn unexpected_interrupt(name: &str, ef: &ExceptionFrame) -> ! {
+    println!("*** {}: unexpected interrupt", name);
+    dump_and_park(ef);
+}
@@ -0,0 +1,245 @@
+/* 
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+    
+*/
+
+use core::ptr;
+
+use crate::dt::Region;
+use crate::heap::align_up;
+use crate::println;
+use crate::early_prints;
+
+#[cfg(feature = "early_print")]
+use crate::print::_early_print_s;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// baremetal_init.s only identity maps the first 4GB
+pub const IDENTITY_MAP_LIMIT: u64 = 0x1_0000_0000;
+
+// the heap never grows by less than this to limit the number of heap regions
+pub const HEAP_GROW_MIN: usize = 256*1024;
+
+const MAX_FRAME_RANGES: usize = 64;
+
+/// Physical page-frame allocator.
+///
+/// Free memory is kept as an address ordered array of page aligned ranges.
+/// The array is static so that frames can be handed out without the heap,
+/// in particular to grow the heap itself.
+pub struct FrameAllocator {
+    free: [Region; MAX_FRAME_RANGES],
+    count: usize,
+}
+
+fn page_align_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn page_align_up(addr: u64) -> u64 {
+    page_align_down(addr.saturating_add(PAGE_SIZE - 1))
+}
+
+impl FrameAllocator {
+
+    pub const fn empty() -> FrameAllocator {
+        FrameAllocator { free: [Region { base: 0, size: 0 }; MAX_FRAME_RANGES], count: 0 }
+    }
+
+    fn insert_at(&mut self, index: usize, region: Region) -> bool {
+        if self.count >= MAX_FRAME_RANGES {
+            early_prints!("\n\nTOO MANY FRAME RANGES, dropping %\n", region.base);
+            return false;
+        }
+        let mut i = self.count;
+        while i > index {
+            self.free[i] = self.free[i - 1];
+            i -= 1;
+        }
+        self.free[index] = region;
+        self.count += 1;
+        return true;
+    }
+
+    fn remove_at(&mut self, index: usize) {
+        for i in index..self.count - 1 {
+            self.free[i] = self.free[i + 1];
+        }
+        self.count -= 1;
+    }
+
+    /// Gives the pages fully contained in [base, base+size) to the allocator.
+    /// Contiguous free ranges are merged.
+    pub fn add_range(&mut self, base: u64, size: u64) -> bool {
+        let start = page_align_up(base);
+        let end = page_align_down(base.saturating_add(size));
+        if end <= start {
+            return false;
+        }
+
+        let index = self.free[..self.count].iter().position(|r| r.base >= start).unwrap_or(self.count);
+        if index < self.count && end > self.free[index].base {
+            early_prints!("\n\nFRAMES ALREADY FREE at %\n", self.free[index].base);
+            return false;
+        }
+        if index > 0 && self.free[index - 1].base + self.free[index - 1].size > start {
+            early_prints!("\n\nFRAMES ALREADY FREE at %\n", start);
+            return false;
+        }
+
+        let merge_previous = index > 0 && self.free[index - 1].base + self.free[index - 1].size == start;
+        let merge_next = index < self.count && self.free[index].base == end;
+
+        if merge_previous && merge_next {
+            self.free[index - 1].size += (end - start) + self.free[index].size;
+            self.remove_at(index);
+        }
+        else if merge_previous {
+            self.free[index - 1].size += end - start;
+        }
+        else if merge_next {
+            self.free[index].base = start;
+            self.free[index].size += end - start;
+        }
+        else {
+            return self.insert_at(index, Region { base: start, size: end - start });
+        }
+        return true;
+    }
+
+    /// Removes every page touching [base, base+size) from the free ranges.
+    pub fn reserve(&mut self, base: u64, size: u64) -> bool {
+        let start = page_align_down(base);
+        let end = page_align_up(base.saturating_add(size));
+        let mut i = 0;
+        while i < self.count {
+            let r = self.free[i];
+            let r_end = r.base + r.size;
+            if end <= r.base || start >= r_end {
+                i += 1;
+                continue;
+            }
+            if start > r.base && end < r_end {
+                // the reservation is in the middle: split
+                if self.count >= MAX_FRAME_RANGES {
+                    early_prints!("\n\nTOO MANY FRAME RANGES, can't reserve %\n", start);
+                    return false;
+                }
+                self.free[i].size = start - r.base;
+                return self.insert_at(i + 1, Region { base: end, size: r_end - end });
+            }
+            if start > r.base {
+                self.free[i].size = start - r.base;
+                i += 1;
+            }
+            else if end < r_end {
+                self.free[i].base = end;
+                self.free[i].size = r_end - end;
+                i += 1;
+            }
+            else {
+                self.remove_at(i);
+            }
+        }
+        return true;
+    }
+
+    /// First fit allocation of `pages` contiguous pages aligned on `align` bytes
+    /// (a power of 2, 0 meaning page aligned), ending below `limit`.
+    pub fn allocate_below(&mut self, pages: usize, align: u64, limit: u64) -> Option<u64> {
+        let size = pages as u64 * PAGE_SIZE;
+        let align = if align > PAGE_SIZE { align } else { PAGE_SIZE };
+        for i in 0..self.count {
+            let r = self.free[i];
+            let start = align_up(r.base as usize, align as usize) as u64;
+            let end = start.saturating_add(size);
+            if end <= r.base + r.size && end <= limit {
+                if self.reserve(start, size) {
+                    return Some(start);
+                }
+                return None;
+            }
+        }
+        None
+    }
+
+    #[allow(dead_code)]
+    pub fn allocate(&mut self, pages: usize, align: u64) -> Option<u64> {
+        self.allocate_below(pages, align, u64::MAX)
+    }
+
+    /// Allocates the pages at a fixed address, failing if any of them is not free.
+    #[allow(dead_code)]
+    pub fn allocate_at(&mut self, address: u64, pages: usize) -> Option<u64> {
+        if address & (PAGE_SIZE - 1) != 0 {
+            return None;
+        }
+        let size = pages as u64 * PAGE_SIZE;
+        let available = self.free[..self.count].iter()
+            .any(|r| address >= r.base && address + size <= r.base + r.size);
+        if available && self.reserve(address, size) {
+            return Some(address);
+        }
+        None
+    }
+
+    #[allow(dead_code)]
+    pub fn free(&mut self, address: u64, pages: usize) -> bool {
+        self.add_range(address, pages as u64 * PAGE_SIZE)
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free[..self.count].iter().map(|r| r.size).sum()
+    }
+
+    pub fn dump(&self) {
+        println!("free frames: {} KiB", self.free_bytes() / 1024);
+        for r in &self.free[..self.count] {
+            println!("    {:#012x}-{:#012x}", r.base, r.base + r.size);
+        }
+    }
+
+}
+
+static mut FRAMES: FrameAllocator = FrameAllocator::empty();
+
+fn frames() -> &'static mut FrameAllocator {
+    unsafe { &mut *ptr::addr_of_mut!(FRAMES) }
+}
+
+pub fn add_range(base: u64, size: u64) -> bool {
+    frames().add_range(base, size)
+}
+
+pub fn reserve(base: u64, size: u64) -> bool {
+    frames().reserve(base, size)
+}
+
+#[allow(dead_code)]
+pub fn allocate(pages: usize, align: u64) -> Option<u64> {
+    frames().allocate(pages, align)
+}
+
+#[allow(dead_code)]
+pub fn allocate_at(address: u64, pages: usize) -> Option<u64> {
+    frames().allocate_at(address, pages)
+}
+
+#[allow(dead_code)]
+pub fn free(address: u64, pages: usize) -> bool {
+    frames().free(address, pages)
+}
+
+pub fn dump() {
+    frames().dump();
+}
+
+/* HeapGrowFn for bare metal: the heap takes identity mapped frames on demand */
+pub fn heap_grow(size: usize) -> Option<(usize, usize)> {
+    let size = core::cmp::max(size, HEAP_GROW_MIN);
+    let pages = size.div_ceil(PAGE_SIZE as usize);
+    let base = frames().allocate_below(pages, 0, IDENTITY_MAP_LIMIT)?;
+    Some((base as usize, pages * PAGE_SIZE as usize))
+}
@@ -5,17 +5,35 @@
 */
 
 use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr;
 
-use crate::{println, early_prints};
+use crate::{log, platforms, println, early_prints};
 
 #[cfg(feature = "early_print")]
 use crate::print::_early_print_s;
 
-/// A simple allocator that allocates memory linearly and ignores freed memory.
-pub struct BumpAllocator {
-    heap_start: usize,
-    heap_end: usize,
-    next: usize,
+/* Free blocks are kept in a singly linked list sorted by address. Each free block
+   starts with a FreeBlock header, so the smallest block that can be handed out
+   or returned is the size of that header. All block boundaries are kept multiple
+   of BLOCK_ALIGN so that splitting a block never leaves an unusable sliver. */
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const BLOCK_ALIGN: usize = size_of::<FreeBlock>();
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+
+// the boot HEAP plus the free RAM ranges found once the memory map is known
+const MAX_HEAP_REGIONS: usize = 16;
+
+/// A first-fit allocator that keeps freed memory on an address ordered free list
+/// and coalesces neighbouring free blocks.
+pub struct FreeListAllocator {
+    regions: [(usize, usize); MAX_HEAP_REGIONS],
+    region_count: usize,
+    head: *mut FreeBlock,
 }
 
     /// Align downwards. Returns the greatest x with alignment `align`
@@ -36,12 +54,19 @@ pub fn align_up(addr: usize, align: usize) -> usize {
     align_down(addr + align - 1, align)
 }
 
-impl BumpAllocator {
+/// Size actually reserved for a layout: the free list needs room for its header
+/// when the block comes back, and block boundaries stay BLOCK_ALIGN aligned.
+fn block_size(layout: &Layout) -> usize {
+    let size = align_up(layout.size(), BLOCK_ALIGN);
+    if size < MIN_BLOCK_SIZE { MIN_BLOCK_SIZE } else { size }
+}
+
+impl FreeListAllocator {
     /// Crate a new UNINITIALIZED heap allocator
     ///
     /// You must initialize this heap using the
     /// [`init`](Self::init) method before using the allocator.
-    pub  const fn empty() -> BumpAllocator {
+    pub  const fn empty() -> FreeListAllocator {
         // the initial next is set to non null to avoid a strange problem
         // if all fields are 0, then the compiler generate a 0 length .data section:
         //0 .text           00004a58 0000000040201000 TEXT
@@ -54,38 +79,197 @@ impl BumpAllocator {
         //1 .rdata          00000f52 0000000040206000 DATA
         //2 .data           00000018 0000000040207000 DATA
         //3 .reloc          000000b4 0000000040208000 DATA
-        BumpAllocator { heap_start: 0, heap_end: 0, next: 0}
+        FreeListAllocator { regions: [(0, 0); MAX_HEAP_REGIONS], region_count: 0, head: ptr::null_mut() }
     }
 
     pub fn init(&mut self, heap_start: usize, heap_size: usize)  {
-        self.heap_start = heap_start;
-        self.heap_end = heap_start+heap_size;
-        self.next = heap_start;
+        self.region_count = 0;
+        self.head = ptr::null_mut();
+        self.add_region(heap_start, heap_size);
+    }
+
+    /// Hands an additional memory range to the allocator. The range must not
+    /// overlap any region already known by the allocator.
+    pub fn add_region(&mut self, start: usize, size: usize) -> bool {
+        let start_aligned = align_up(start, BLOCK_ALIGN);
+        let end = align_down(start + size, BLOCK_ALIGN);
+        if end <= start_aligned || end - start_aligned < MIN_BLOCK_SIZE {
+            return false;
+        }
+        if let Some(region) = self.regions[..self.region_count].iter_mut().find(|r| r.1 == start_aligned) {
+            // firmware often hands out contiguous chunks: just extend the region
+            region.1 = end;
+        }
+        else if self.region_count >= MAX_HEAP_REGIONS {
+            early_prints!("\n\nTOO MANY HEAP REGIONS, ignoring %\n", start as u64);
+            return false;
+        }
+        else {
+            self.regions[self.region_count] = (start_aligned, end);
+            self.region_count += 1;
+        }
+        unsafe {
+            self.insert_free(start_aligned, end - start_aligned);
+        }
+        return true;
+    }
+
+    /// Walks the free list: returns (free bytes, largest free block, number of free blocks).
+    fn free_stats(&self) -> (usize, usize, usize) {
+        let mut total = 0;
+        let mut largest = 0;
+        let mut count = 0;
+        let mut current = self.head;
+        while !current.is_null() {
+            unsafe {
+                let size = (*current).size;
+                total += size;
+                if size > largest {
+                    largest = size;
+                }
+                count += 1;
+                current = (*current).next;
+            }
+        }
+        (total, largest, count)
+    }
+
+    fn capacity(&self) -> usize {
+        self.regions[..self.region_count].iter().map(|&(start, end)| end - start).sum()
+    }
+
+    fn can_add_region(&self) -> bool {
+        self.region_count < MAX_HEAP_REGIONS
+    }
+
+    fn owns(&self, addr: usize) -> bool {
+        self.regions[..self.region_count].iter().any(|&(start, end)| addr >= start && addr < end)
+    }
+
+    /// Puts the block [addr, addr+size) back on the free list, merging it with
+    /// the previous and/or next free block when they are contiguous.
+    unsafe fn insert_free(&mut self, addr: usize, size: usize) {
+        let mut previous: *mut FreeBlock = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() && (current as usize) < addr {
+            previous = current;
+            current = (*current).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next: current });
+
+        // merge with the following block
+        if !current.is_null() && addr + size == current as usize {
+            (*block).size += (*current).size;
+            (*block).next = (*current).next;
+        }
+
+        // merge with the preceding block
+        if previous.is_null() {
+            self.head = block;
+        }
+        else if previous as usize + (*previous).size == addr {
+            (*previous).size += (*block).size;
+            (*previous).next = (*block).next;
+        }
+        else {
+            (*previous).next = block;
+        }
+    }
+
+    /// First fit search: carves the request out of the first free block that can
+    /// hold it once aligned. Leading and trailing leftovers stay on the free list.
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = block_size(&layout);
+        let align = if layout.align() > BLOCK_ALIGN { layout.align() } else { BLOCK_ALIGN };
+
+        let mut previous: *mut FreeBlock = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() {
+            let block_start = current as usize;
+            let block_end = block_start + (*current).size;
+            let next = (*current).next;
+
+            let mut alloc_start = align_up(block_start, align);
+            if alloc_start != block_start && alloc_start - block_start < MIN_BLOCK_SIZE {
+                // the leading part would be too small to be kept as a free block
+                alloc_start = align_up(block_start + MIN_BLOCK_SIZE, align);
+            }
+            let alloc_end = alloc_start.saturating_add(size);
+
+            if alloc_end <= block_end {
+                // unlink the block, then give back what is not used
+                if previous.is_null() {
+                    self.head = next;
+                }
+                else {
+                    (*previous).next = next;
+                }
+                if alloc_end < block_end {
+                    self.insert_free(alloc_end, block_end - alloc_end);
+                }
+                if alloc_start > block_start {
+                    self.insert_free(block_start, alloc_start - block_start);
+                }
+                return alloc_start as *mut u8;
+            }
+
+            previous = current;
+            current = next;
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let addr = ptr as usize;
+        if !self.owns(addr) {
+            early_prints!("\n\nFREE OUTSIDE OF HEAP %\n", addr as u64);
+            return;
+        }
+        self.insert_free(addr, block_size(&layout));
     }
 
 }
 
-unsafe impl GlobalAlloc for BumpAllocator {
+unsafe impl GlobalAlloc for FreeListAllocator {
     
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let alloc_start = align_up(self.next, layout.align());
-        let alloc_end = alloc_start.saturating_add(layout.size());
-        ALLOC_COUNT += 1;
-        ALLOC_SIZE += layout.size();
         //early_prints!("alloc % bytes\n", layout.size() as u64);
-        if alloc_end <= self.heap_end {
-            HEAP.next = alloc_end;
-            //println!("alloc ok @{:#x}", alloc_start);
-            return alloc_start as *mut u8;
-        } else {
-            //println!("alloc problem @{:#x}", alloc_start);
+        let heap = &mut *ptr::addr_of_mut!(HEAP);
+        let mut result = heap.allocate(layout);
+        if result.is_null() && heap.can_add_region() {
+            if let Some(grow) = HEAP_GROW {
+                // room for the request even if the new range is badly aligned
+                let request = block_size(&layout) + layout.align() + MIN_BLOCK_SIZE;
+                if let Some((start, size)) = grow(request) {
+                    heap.add_region(start, size);
+                    result = heap.allocate(layout);
+                }
+            }
+        }
+        if result.is_null() {
+            //println!("alloc problem for {} bytes", layout.size());
             early_prints!("\n\nOUT OF MEMORY\n", 0);
-            return 0 as * mut u8;
+            ALLOC_FAILURES += 1;
+        }
+        else {
+            ALLOC_COUNT += 1;
+            ALLOC_SIZE += layout.size();
+            ALLOC_HISTOGRAM[histogram_bucket(layout.size())] += 1;
+            let live = ALLOC_SIZE - FREE_SIZE;
+            if live > PEAK_SIZE {
+                PEAK_SIZE = live;
+            }
         }
+        return result;
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        // do nothing, leak memory
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        FREE_COUNT += 1;
+        FREE_SIZE += layout.size();
+        let heap = &mut *ptr::addr_of_mut!(HEAP);
+        heap.deallocate(ptr, layout);
     }
 }
 
@@ -100,16 +284,170 @@ unsafe impl GlobalAlloc for BumpAllocator {
 4020239c: 8d fd ff 97   bl      0x402019d0 <.text+0x9d0>
  */
 
- #[global_allocator]
-static  mut HEAP: BumpAllocator = BumpAllocator::empty();
+ #[cfg_attr(not(feature = "heap_debug"), global_allocator)]
+static  mut HEAP: FreeListAllocator = FreeListAllocator::empty();
+
+#[cfg(feature = "heap_debug")]
+#[global_allocator]
+static DEBUG_HEAP: crate::heap_debug::DebugAllocator = crate::heap_debug::DebugAllocator {};
 pub static mut ALLOC_COUNT: u64 = 0;
 pub static mut ALLOC_SIZE: usize = 0;
+pub static mut FREE_COUNT: u64 = 0;
+pub static mut FREE_SIZE: usize = 0;
+pub static mut PEAK_SIZE: usize = 0;
+pub static mut ALLOC_FAILURES: u64 = 0;
+
+/* allocation sizes up to 16 bytes, up to 32 bytes... the last bucket takes everything above 256KiB */
+const HISTOGRAM_BUCKETS: usize = 16;
+pub static mut ALLOC_HISTOGRAM: [u64; HISTOGRAM_BUCKETS] = [0; HISTOGRAM_BUCKETS];
 
+fn histogram_bucket(size: usize) -> usize {
+    let mut bucket = 0;
+    while bucket < HISTOGRAM_BUCKETS - 1 && size > (16 << bucket) {
+        bucket += 1;
+    }
+    bucket
+}
+
+/// Called when the free list cannot satisfy a request: returns a new memory
+/// range of at least the requested size, or None when nothing more is available.
+pub type HeapGrowFn = fn(usize) -> Option<(usize, usize)>;
+
+static mut HEAP_GROW: Option<HeapGrowFn> = None;
+
+/// Direct access to the free list allocator for allocator wrappers.
+#[cfg(feature = "heap_debug")]
+pub unsafe fn raw_alloc(layout: Layout) -> *mut u8 {
+    (*ptr::addr_of!(HEAP)).alloc(layout)
+}
+
+#[cfg(feature = "heap_debug")]
+pub unsafe fn raw_dealloc(ptr: *mut u8, layout: Layout) {
+    (*ptr::addr_of!(HEAP)).dealloc(ptr, layout)
+}
+
+pub struct HeapReport {
+    pub capacity: usize,
+    pub regions: usize,
+    pub live: usize,
+    pub peak: usize,
+    pub alloc_count: u64,
+    pub free_count: u64,
+    pub failures: u64,
+    pub free: usize,
+    pub largest_free: usize,
+    pub free_blocks: usize,
+    pub histogram: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl HeapReport {
+
+    /// Percentage of free memory that can't be handed out as one block.
+    pub fn fragmentation(&self) -> usize {
+        if self.free == 0 {
+            return 0;
+        }
+        100 - (self.largest_free * 100) / self.free
+    }
+
+    pub fn print(&self) {
+        println!("Heap report:");
+        println!("    capacity      {} bytes in {} region(s)", self.capacity, self.regions);
+        println!("    live          {} bytes", self.live);
+        println!("    peak          {} bytes", self.peak);
+        println!("    allocations   {} ({} freed, {} failed)", self.alloc_count, self.free_count, self.failures);
+        println!("    free          {} bytes in {} block(s)", self.free, self.free_blocks);
+        println!("    largest free  {} bytes", self.largest_free);
+        println!("    fragmentation {}%", self.fragmentation());
+        println!("    sizes:");
+        for (bucket, count) in self.histogram.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            if bucket == HISTOGRAM_BUCKETS - 1 {
+                println!("        >  {:>7}: {}", 16 << (bucket - 1), count);
+            }
+            else {
+                println!("        <= {:>7}: {}", 16 << bucket, count);
+            }
+        }
+    }
+
+}
+
+/// Snapshot of the heap usage, can be taken at any time.
+pub fn heap_report() -> HeapReport {
+    unsafe {
+        let heap = &*ptr::addr_of!(HEAP);
+        let (free, largest_free, free_blocks) = heap.free_stats();
+        HeapReport {
+            capacity: heap.capacity(),
+            regions: heap.region_count,
+            live: ALLOC_SIZE - FREE_SIZE,
+            peak: PEAK_SIZE,
+            alloc_count: ALLOC_COUNT,
+            free_count: FREE_COUNT,
+            failures: ALLOC_FAILURES,
+            free,
+            largest_free,
+            free_blocks,
+            histogram: *ptr::addr_of!(ALLOC_HISTOGRAM),
+        }
+    }
+}
+
+/// What to do once the heap can't satisfy an allocation.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OomPolicy {
+    /// Dump the heap state and spin forever.
+    Halt,
+    /// Dump the heap state and reset the board through the platform.
+    Reset,
+    /// Dump the heap state and give control back to the loader with an error status.
+    Exit,
+}
+
+impl OomPolicy {
+    /// Parses the value of the `oom=` command line option.
+    pub fn from_name(name: &str) -> Option<OomPolicy> {
+        match name {
+            "halt" => Some(OomPolicy::Halt),
+            "reset" => Some(OomPolicy::Reset),
+            "exit" => Some(OomPolicy::Exit),
+            _ => None
+        }
+    }
+}
+
+static mut OOM_POLICY: OomPolicy = OomPolicy::Halt;
+
+pub fn set_oom_policy(policy: OomPolicy) {
+    unsafe {
+        OOM_POLICY = policy;
+    }
+}
+
+/* Nothing in there allocates: the report lives on the stack and the console
+   writes through the already installed logger. */
 #[alloc_error_handler]
-fn oom(_: Layout) -> ! {
+fn oom(layout: Layout) -> ! {
     early_prints!("\n\nOUT OF MEMORY\n", 0);
-    println!("Out of memory");
-    loop {}
+    let policy = unsafe { OOM_POLICY };
+    if log::tty().is_some() {
+        println!("Out of memory: failed to allocate {} bytes aligned on {}", layout.size(), layout.align());
+        heap_report().print();
+    }
+    if let Some(platform) = platforms::current() {
+        match policy {
+            OomPolicy::Halt => {},
+            OomPolicy::Reset => platform.reset(),
+            OomPolicy::Exit => platform.exit(-1),
+        }
+        platform.park();
+    }
+    loop {
+        core::hint::spin_loop();
+    }
 }
 
 pub fn heap_init(heap_start: usize, heap_size: usize)
@@ -118,4 +456,13 @@ pub fn heap_init(heap_start: usize, heap_size: usize)
 
         HEAP.init(heap_start, heap_size);
     }
+}
+
+/// Installs (or removes with None) the function used to get more memory when
+/// the heap is exhausted. Once removed, the heap is frozen to the ranges it owns.
+pub fn heap_set_grow(grow: Option<HeapGrowFn>)
+{
+    unsafe {
+        HEAP_GROW = grow;
+    }
 }
\ No newline at end of file
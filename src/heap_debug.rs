@@ -0,0 +1,279 @@
+/* 
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+    
+*/
+
+/*
+Debug allocator, enabled with the heap_debug feature.
+
+Each allocation is wrapped as follows (the block comes from the regular heap):
+
+    +--------------+-----------------+-----------+----------------+
+    | AllocHeader  | front red zone  | user data | back red zone  |
+    +--------------+-----------------+-----------+----------------+
+    ^ block start                    ^ returned pointer
+
+The red zones are filled with RED_BYTE and checked on free and on demand.
+Freed blocks are poisoned and kept in a small quarantine before being really
+freed: a poison byte that changed while in quarantine is a use after free.
+ALLOC_COUNT/ALLOC_SIZE account for the wrapped blocks, overhead included.
+
+Corruption found while freeing is reported on the console sink only and the
+CPU is halted: a panic or the other sinks may allocate, and re-enter the
+allocator that just found its own state broken.
+*/
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::mem::size_of;
+use core::ptr;
+
+use crate::backtrace;
+use crate::heap::{self, align_up};
+use crate::log;
+use crate::println;
+use crate::early_prints;
+
+#[cfg(feature = "early_print")]
+use crate::print::_early_print_s;
+
+const RED_ZONE: usize = 16;
+const RED_BYTE: u8 = 0xFD;
+const POISON_BYTE: u8 = 0xDD;
+const FRESH_BYTE: u8 = 0xCD;
+
+const LIVE_MAGIC: u64 = 0xA110_CA7E_D0D0_0001;
+const FREED_MAGIC: u64 = 0xA110_CA7E_DEAD_0002;
+
+// return addresses recorded for each allocation (frame pointer chain)
+const SITE_DEPTH: usize = 4;
+
+const QUARANTINE_SIZE: usize = 64;
+
+#[repr(C)]
+struct AllocHeader {
+    magic: u64,
+    size: usize,
+    offset: usize,
+    prev: *mut AllocHeader,
+    next: *mut AllocHeader,
+    site: [u64; SITE_DEPTH],
+}
+
+pub struct DebugAllocator {
+}
+
+static mut LIVE: *mut AllocHeader = ptr::null_mut();
+static mut QUARANTINE: [(usize, usize, usize); QUARANTINE_SIZE] = [(0, 0, 0); QUARANTINE_SIZE];
+static mut QUARANTINE_NEXT: usize = 0;
+
+/* offset of the user data from the start of the block */
+fn user_offset(align: usize) -> usize {
+    align_up(size_of::<AllocHeader>() + RED_ZONE, align)
+}
+
+fn block_layout(layout: &Layout) -> Option<(Layout, usize)> {
+    let align = core::cmp::max(layout.align(), size_of::<u128>());
+    let offset = user_offset(align);
+    let size = offset.checked_add(layout.size())?.checked_add(RED_ZONE)?;
+    let inner = Layout::from_size_align(size, align).ok()?;
+    Some((inner, offset))
+}
+
+/* the frame pointer chain, only followed within the boot stack */
+#[inline(always)]
+fn capture_site() -> [u64; SITE_DEPTH] {
+    let mut site = [0u64; SITE_DEPTH];
+    let (stack_low, stack_high) = match backtrace::boot_stack() {
+        Some(bounds) => bounds,
+        None => return site,
+    };
+    let fp: u64;
+    unsafe {
+        asm!("mov {}, x29", out(reg) fp);
+    }
+    let mut depth = 0;
+    backtrace::walk(fp, stack_low, stack_high, |address| {
+        if depth < SITE_DEPTH {
+            site[depth] = address;
+            depth += 1;
+        }
+    });
+    site
+}
+
+/* straight to the console: formatting does not allocate, some sinks could */
+fn report(what: &str, header: *const AllocHeader, user: usize) {
+    unsafe {
+        let site = (*header).site;
+        log::write_fmt_to(log::CONSOLE_SINK, format_args!("\nHEAP: {} for block {:#x} ({} bytes)\n", what, user, (*header).size));
+        log::write_fmt_to(log::CONSOLE_SINK, format_args!("    allocated from {:#x} {:#x} {:#x} {:#x}\n", site[0], site[1], site[2], site[3]));
+    }
+}
+
+/* the allocator can't be trusted anymore: no panic, it would allocate */
+fn halt(user: usize) -> ! {
+    early_prints!("\nHEAP: corruption at block %, halted\n", user as u64);
+    log::write_fmt_to(log::CONSOLE_SINK, format_args!("HEAP: corruption at block {:#x}, halted\n", user));
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn fill(start: usize, len: usize, value: u8) {
+    ptr::write_bytes(start as *mut u8, value, len);
+}
+
+unsafe fn first_mismatch(start: usize, len: usize, value: u8) -> Option<usize> {
+    (0..len).find(|&i| *((start + i) as *const u8) != value)
+}
+
+/* returns true when both red zones are intact */
+unsafe fn check_red_zones(header: *const AllocHeader, offset: usize) -> bool {
+    let block = header as usize;
+    let user = block + offset;
+    let front_start = block + size_of::<AllocHeader>();
+    let front_ok = first_mismatch(front_start, user - front_start, RED_BYTE).is_none();
+    let back_ok = first_mismatch(user + (*header).size, RED_ZONE, RED_BYTE).is_none();
+    if !front_ok {
+        report("front red zone overwritten", header, user);
+    }
+    if !back_ok {
+        report("back red zone overwritten", header, user);
+    }
+    front_ok && back_ok
+}
+
+/* returns true when the quarantined block is still fully poisoned */
+unsafe fn check_poison(block: usize, size: usize, offset: usize) -> bool {
+    let header = block as *const AllocHeader;
+    let user = block + offset;
+    if (*header).magic != FREED_MAGIC {
+        report("header of freed block overwritten", header, user);
+        return false;
+    }
+    let poisoned = block + size_of::<AllocHeader>();
+    if let Some(i) = first_mismatch(poisoned, size - size_of::<AllocHeader>(), POISON_BYTE) {
+        report("write after free", header, user);
+        log::write_fmt_to(log::CONSOLE_SINK, format_args!("    first modified byte at {:#x}\n", poisoned + i));
+        return false;
+    }
+    true
+}
+
+unsafe fn unlink(header: *mut AllocHeader) {
+    if (*header).prev.is_null() {
+        LIVE = (*header).next;
+    }
+    else {
+        (*(*header).prev).next = (*header).next;
+    }
+    if !(*header).next.is_null() {
+        (*(*header).next).prev = (*header).prev;
+    }
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (inner, offset) = match block_layout(&layout) {
+            Some(l) => l,
+            None => return ptr::null_mut(),
+        };
+        let block = heap::raw_alloc(inner);
+        if block.is_null() {
+            return block;
+        }
+        let block = block as usize;
+        let user = block + offset;
+        let header = block as *mut AllocHeader;
+        header.write(AllocHeader {
+            magic: LIVE_MAGIC,
+            size: layout.size(),
+            offset,
+            prev: ptr::null_mut(),
+            next: LIVE,
+            site: capture_site(),
+        });
+        if !LIVE.is_null() {
+            (*LIVE).prev = header;
+        }
+        LIVE = header;
+
+        fill(block + size_of::<AllocHeader>(), offset - size_of::<AllocHeader>(), RED_BYTE);
+        fill(user, layout.size(), FRESH_BYTE);
+        fill(user + layout.size(), RED_ZONE, RED_BYTE);
+        user as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (inner, offset) = match block_layout(&layout) {
+            Some(l) => l,
+            None => return,
+        };
+        let user = ptr as usize;
+        let block = user - offset;
+        let header = block as *mut AllocHeader;
+
+        if (*header).magic == FREED_MAGIC {
+            report("double free", header, user);
+            halt(user);
+        }
+        if (*header).magic != LIVE_MAGIC {
+            log::write_fmt_to(log::CONSOLE_SINK, format_args!("\nHEAP: free of unknown block {:#x} ({} bytes)\n", user, layout.size()));
+            halt(user);
+        }
+        if (*header).size != layout.size() {
+            report("freed with a different size", header, user);
+            halt(user);
+        }
+        if !check_red_zones(header, offset) {
+            halt(user);
+        }
+
+        unlink(header);
+        (*header).magic = FREED_MAGIC;
+        fill(block + size_of::<AllocHeader>(), inner.size() - size_of::<AllocHeader>(), POISON_BYTE);
+
+        // the oldest quarantined block is really freed
+        let (old_block, old_size, old_align) = QUARANTINE[QUARANTINE_NEXT];
+        QUARANTINE[QUARANTINE_NEXT] = (block, inner.size(), inner.align());
+        QUARANTINE_NEXT = (QUARANTINE_NEXT + 1) % QUARANTINE_SIZE;
+        if old_block != 0 {
+            if !check_poison(old_block, old_size, user_offset(old_align)) {
+                halt(old_block + user_offset(old_align));
+            }
+            heap::raw_dealloc(old_block as *mut u8, Layout::from_size_align_unchecked(old_size, old_align));
+        }
+    }
+}
+
+/// Checks the red zones of every live allocation and the poison of quarantined
+/// blocks. Returns the number of corrupted blocks, each one being reported.
+pub fn heap_check() -> usize {
+    let mut corrupted = 0;
+    unsafe {
+        let mut header = LIVE;
+        while !header.is_null() {
+            if (*header).magic != LIVE_MAGIC {
+                println!("\nHEAP: header of live block {:#x} overwritten", header as usize);
+                corrupted += 1;
+                // the list can't be trusted anymore
+                break;
+            }
+            if !check_red_zones(header, (*header).offset) {
+                corrupted += 1;
+            }
+            header = (*header).next;
+        }
+        for i in 0..QUARANTINE_SIZE {
+            let (block, size, align) = QUARANTINE[i];
+            if block != 0 && !check_poison(block, size, user_offset(align)) {
+                corrupted += 1;
+            }
+        }
+    }
+    println!("HEAP check: {} corrupted block(s)", corrupted);
+    corrupted
+}
@@ -4,11 +4,57 @@
     
 */
 
+use core::ptr;
+
 use alloc::{boxed::Box, string::String};
 
 pub trait TTY {
     fn get_unprinted(&self) -> String;
+    /// The receive side of the console, if the device has one.
+    fn as_input(&mut self) -> Option<&mut dyn ConsoleInput> {
+        None
+    }
  }
+
+#[allow(dead_code)]
+pub trait ConsoleInput {
+    /// Returns the next received byte, None if nothing is pending.
+    fn try_read(&mut self) -> Option<u8>;
+
+    /// Waits for a line terminated by CR or LF, echoing what is typed.
+    /// Backspace and DEL erase the previous character. Returns the line length,
+    /// the terminator is not stored and input beyond the buffer is ignored.
+    fn read_line(&mut self, buffer: &mut [u8]) -> usize {
+        let mut length = 0;
+        loop {
+            let c = match self.try_read() {
+                Some(c) => c,
+                None => {
+                    core::hint::spin_loop();
+                    continue;
+                }
+            };
+            match c {
+                b'\r' | b'\n' => {
+                    crate::print!("\n");
+                    return length;
+                }
+                0x08 | 0x7f => {
+                    if length > 0 {
+                        length -= 1;
+                        crate::print!("\x08 \x08");
+                    }
+                }
+                c if (0x20..0x7f).contains(&c) && length < buffer.len() => {
+                    buffer[length] = c;
+                    length += 1;
+                    crate::print!("{}", c as char);
+                }
+                _ => {}
+            }
+        }
+    }
+}
  
 pub mod interface {
     pub use core::fmt::Write;
@@ -19,23 +65,336 @@ pub trait Logger: core::fmt::Write + TTY {}
 // blanket implementation
 impl<TargetLogger: core::fmt::Write + TTY> Logger for TargetLogger {}
 
+/*  Output goes to every registered sink, each one with its own maximum level:
+    println! reaches all of them, error!..trace! only the sinks whose level is
+    high enough.  Slot 0 is the console, the one set_target changes and that
+    tty(), input() and get_unprinted() refer to: the previous console is kept
+    as an ordinary sink, so the boot buffer keeps its copy once the UART is
+    found.
+    The slots only hold references: printing never allocates, so it can be used
+    before heap_init and from the panic, OOM and exception handlers.
+*/
+
+pub type SinkId = usize;
+
+pub const CONSOLE_SINK: SinkId = 0;
+const MAX_SINKS: usize = 8;
+
+struct Sink {
+    logger: Option<&'static mut dyn Logger>,
+    // true when the logger was handed over as a Box and must be dropped on removal
+    boxed: bool,
+    level: u8,
+}
+
+const NO_SINK: Sink = Sink { logger: None, boxed: false, level: Level::Trace as u8 };
+
 // that variable happen to be NOT initialized
-static mut TTY_TARGET: Option<Box<dyn Logger>> = None;
+static mut SINKS: [Sink; MAX_SINKS] = [NO_SINK; MAX_SINKS];
 
+fn sinks() -> &'static mut [Sink; MAX_SINKS] {
+    unsafe { &mut *ptr::addr_of_mut!(SINKS) }
+}
+
+fn install(id: SinkId, logger: Option<&'static mut dyn Logger>, boxed: bool, level: u8) {
+    let sink = &mut sinks()[id];
+    let previous = core::mem::replace(sink, Sink { logger, boxed, level });
+    if let (Some(previous), true) = (previous.logger, previous.boxed) {
+        unsafe { drop(Box::from_raw(previous as *mut dyn Logger)) };
+    }
+}
+
+fn add(logger: &'static mut dyn Logger, boxed: bool, level: Level) -> Option<SinkId> {
+    let id = (CONSOLE_SINK + 1..MAX_SINKS).find(|&id| sinks()[id].logger.is_none())?;
+    install(id, Some(logger), boxed, level as u8);
+    Some(id)
+}
 
 pub fn get_unprinted() -> String {
-    unsafe {
-        let target = TTY_TARGET.as_mut().unwrap();
-        target.get_unprinted()
+    match tty() {
+        Some(target) => target.get_unprinted(),
+        None => String::new(),
     }
 }
 
+/* the previous console goes on as a sink, it is dropped only if no slot is left */
+fn change_console(target: Option<&'static mut dyn Logger>, boxed: bool) {
+    let console = &mut sinks()[CONSOLE_SINK];
+    let level = console.level;
+    let previous = core::mem::replace(console, Sink { logger: target, boxed, level });
+    if let Some(logger) = previous.logger {
+        match (CONSOLE_SINK + 1..MAX_SINKS).find(|&id| sinks()[id].logger.is_none()) {
+            Some(id) => sinks()[id] = Sink { logger: Some(logger), boxed: previous.boxed, level: Level::Trace as u8 },
+            None if previous.boxed => unsafe { drop(Box::from_raw(logger as *mut dyn Logger)) },
+            None => {}
+        }
+    }
+}
+
+/// Makes a heap allocated logger the console, the console level is kept.
 pub fn set_target(target: Option<Box<dyn Logger>>) {
+    let boxed = target.is_some();
+    change_console(target.map(|t| Box::leak(t) as &'static mut dyn Logger), boxed);
+}
+
+/// Makes a logger living in static storage the console, no heap needed.
+pub fn set_static_target(target: &'static mut dyn Logger) {
+    change_console(Some(target), false);
+}
+
+/// Mirrors the output to another logger. None when all the slots are taken.
+pub fn add_sink(logger: Box<dyn Logger>, level: Level) -> Option<SinkId> {
+    add(Box::leak(logger), true, level)
+}
+
+/// Same as add_sink for a logger living in static storage.
+pub fn add_static_sink(logger: &'static mut dyn Logger, level: Level) -> Option<SinkId> {
+    add(logger, false, level)
+}
+
+/// Stops mirroring to the sink, dropping it if it was boxed.
+pub fn remove_sink(id: SinkId) {
+    if id < MAX_SINKS {
+        install(id, None, false, Level::Trace as u8);
+    }
+}
+
+/// Most verbose level written to the sink; println! output is not filtered.
+pub fn set_sink_level(id: SinkId, level: Level) {
+    if id < MAX_SINKS {
+        sinks()[id].level = level as u8;
+    }
+}
+
+#[doc(hidden)]
+pub fn write_fmt(level: Option<Level>, args: core::fmt::Arguments) {
+    for sink in sinks().iter_mut() {
+        if let Some(logger) = sink.logger.as_deref_mut() {
+            if level.map_or(true, |l| l as u8 <= sink.level) {
+                let _ = logger.write_fmt(args);
+            }
+        }
+    }
+}
+
+/// Writes to the sink `id` only: replays the output a new sink has missed.
+pub fn write_fmt_to(id: SinkId, args: core::fmt::Arguments) {
+    if let Some(logger) = sinks().get_mut(id).and_then(|sink| sink.logger.as_deref_mut()) {
+        let _ = logger.write_fmt(args);
+    }
+}
+
+fn wanted_by_a_sink(level: Level) -> bool {
+    sinks().iter().any(|sink| sink.logger.is_some() && level as u8 <= sink.level)
+}
+
+pub fn tty() -> Option<&'static mut dyn Logger> {
+    sinks()[CONSOLE_SINK].logger.as_deref_mut()
+}
+
+/// Input side of the current console (it follows log::set_target), or of the
+/// first sink that can receive when the console can't.
+pub fn input() -> Option<&'static mut dyn ConsoleInput> {
+    sinks().iter_mut().find_map(|sink| sink.logger.as_deref_mut()?.as_input())
+}
+
+
+/*  Leveled logging.
+
+    error!/warn!/info!/debug!/trace! go through the same sinks as println!,
+    prefixed with the time since the counter started, the EL, the CPU, the level
+    and the module.  Filters are given as a comma separated list of either a
+    level (the default) or module=level, for instance "info,heap=debug,frames=off".
+    The build time filter comes from the BAREKIT_LOG environment variable and is
+    overridden by the log= word of the command line. The module filters apply
+    first, then the level of each sink.
+*/
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "E",
+            Level::Warn => "W",
+            Level::Info => "I",
+            Level::Debug => "D",
+            Level::Trace => "T",
+        }
+    }
+}
+
+const LEVEL_OFF: u8 = 0;
+const DEFAULT_LEVEL: u8 = Level::Info as u8;
+const BUILD_FILTER: Option<&str> = option_env!("BAREKIT_LOG");
+const MAX_LOG_FILTERS: usize = 16;
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+// fixed size so that filtering works before the heap and in fault handlers
+struct LevelFilters {
+    ready: bool,
+    default: u8,
+    modules: [(&'static str, u8); MAX_LOG_FILTERS],
+    count: usize,
+}
+
+static mut FILTERS: LevelFilters = LevelFilters {
+    ready: false,
+    default: DEFAULT_LEVEL,
+    modules: [("", LEVEL_OFF); MAX_LOG_FILTERS],
+    count: 0,
+};
+
+fn parse_level(name: &str) -> Option<u8> {
+    if name == "off" {
+        return Some(LEVEL_OFF);
+    }
+    Level::from_name(name).map(|level| level as u8)
+}
+
+impl LevelFilters {
+
+    fn apply(&mut self, spec: &'static str) {
+        for item in spec.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            match item.split_once('=') {
+                None => {
+                    if let Some(level) = parse_level(item) {
+                        self.default = level;
+                    }
+                }
+                Some((module, level)) => {
+                    let Some(level) = parse_level(level) else { continue };
+                    let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+                    if let Some(slot) = self.modules[..self.count].iter_mut().find(|(m, _)| *m == module) {
+                        slot.1 = level;
+                    }
+                    else if self.count < MAX_LOG_FILTERS {
+                        self.modules[self.count] = (module, level);
+                        self.count += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /* the longest matching module prefix wins: "drivers::pl011" before "drivers" */
+    fn level_for(&self, module: &str) -> u8 {
+        let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+        let mut best: Option<(usize, u8)> = None;
+        for &(prefix, level) in &self.modules[..self.count] {
+            let matches = module == prefix
+                || (module.starts_with(prefix) && module[prefix.len()..].starts_with("::"));
+            if matches && best.map_or(true, |(len, _)| prefix.len() > len) {
+                best = Some((prefix.len(), level));
+            }
+        }
+        best.map_or(self.default, |(_, level)| level)
+    }
+}
+
+fn filters() -> &'static mut LevelFilters {
+    unsafe {
+        let filters = &mut *ptr::addr_of_mut!(FILTERS);
+        if !filters.ready {
+            filters.ready = true;
+            if let Some(spec) = BUILD_FILTER {
+                filters.apply(spec);
+            }
+        }
+        filters
+    }
+}
+
+/// Adds the filters of `spec` on top of the current ones (build time filters first).
+pub fn set_filter(spec: &'static str) {
+    filters().apply(spec);
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    level as u8 <= filters().level_for(module)
+}
+
+/* microseconds since the system counter started */
+fn timestamp_us() -> u64 {
+    let count: u64;
+    let frequency: u64;
     unsafe {
-        TTY_TARGET = target
-    };
+        core::arch::asm!("mrs {}, CNTVCT_EL0", out(reg) count);
+        core::arch::asm!("mrs {}, CNTFRQ_EL0", out(reg) frequency);
+    }
+    if frequency == 0 {
+        return 0;
+    }
+    ((count as u128 * 1_000_000) / frequency as u128) as u64
 }
 
-pub fn tty() -> &'static mut Option<Box<dyn Logger>>{
-    unsafe {&mut TTY_TARGET}
-}
\ No newline at end of file
+fn current_cpu() -> u64 {
+    let mpidr: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, MPIDR_EL1", out(reg) mpidr);
+    }
+    // Aff1.Aff0 is enough to tell the cores of a cluster apart
+    mpidr & 0xffff
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &'static str, args: core::fmt::Arguments) {
+    if !enabled(level, module) || !wanted_by_a_sink(level) {
+        return;
+    }
+    let us = timestamp_us();
+    let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+    write_fmt(Some(level), format_args!("[{:>5}.{:06}] EL{} CPU{:x} {} {}: {}\n",
+        us / 1_000_000, us % 1_000_000,
+        crate::processor::get_current_el(), current_cpu(),
+        level.name(), module, args));
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, module_path!(), format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
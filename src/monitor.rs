@@ -0,0 +1,472 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+Interactive monitor over the boot console, entered when the command line
+contains the "monitor" word. It exposes the diagnostics of run.rs without
+having to edit and rebuild:
+
+    help                          this list
+    regs                          system registers of the current EL
+    vobj                          CPU description for the emulator (-vobj)
+    md <addr> [len]               hexdump, 256 bytes by default
+    peek <addr> [1|2|4|8]         read a value, 8 bytes by default
+    poke <addr> <value> [1|2|4|8] write a value
+    sym <addr>                    symbol containing an address
+    dt [path]                     device tree nodes, or the properties of a node
+    pt                            page tables of the low memory
+    heap                          heap report
+    frames                        free physical frames
+    bench                         sha256 CPU benchmark
+    load <file> [addr]            load a host file through semihosting
+    run                           the regular payload
+    reset                         platform reset
+    exit [code]                   leave the monitor with a return code
+
+Lines are edited with backspace, ctrl-U erases the line, ctrl-C cancels it and
+the up/down arrows walk the history.
+*/
+
+use core::arch::asm;
+use core::ptr;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use fdt_rs::prelude::PropReader;
+
+use crate::cmdline::parse_u64;
+use crate::dt;
+use crate::dt::DeviceTree;
+use crate::frames;
+use crate::heap;
+use crate::log;
+use crate::log::ConsoleInput;
+use crate::platforms::PlatformOperations;
+use crate::processor;
+use crate::run;
+use crate::semihosting;
+use crate::semihosting::HostFile;
+use crate::symbols;
+use crate::{print, println, warn};
+
+const PROMPT: &str = "barekit> ";
+const LINE_SIZE: usize = 128;
+const HISTORY_SIZE: usize = 16;
+const DEFAULT_DUMP_SIZE: u64 = 256;
+
+struct LineEditor {
+    history: Vec<String>,
+}
+
+enum Key {
+    Char(u8),
+    Up,
+    Down,
+    Other,
+}
+
+fn read_key(input: &mut dyn ConsoleInput) -> Key {
+    let mut wait = || loop {
+        if let Some(c) = input.try_read() {
+            return c;
+        }
+        core::hint::spin_loop();
+    };
+    let c = wait();
+    if c != 0x1b {
+        return Key::Char(c);
+    }
+    // ANSI escape sequence: ESC [ A/B/C/D
+    if wait() != b'[' {
+        return Key::Other;
+    }
+    match wait() {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        _ => Key::Other,
+    }
+}
+
+fn redraw(line: &str) {
+    print!("\r{}{}\x1b[K", PROMPT, line);
+}
+
+impl LineEditor {
+
+    fn new() -> Self {
+        LineEditor { history: Vec::new() }
+    }
+
+    /* None when the line is cancelled with ctrl-C */
+    fn read_line(&mut self, input: &mut dyn ConsoleInput) -> Option<String> {
+        let mut line = String::new();
+        // index in history while browsing it, history.len() is the line being typed
+        let mut position = self.history.len();
+        print!("{}", PROMPT);
+        loop {
+            match read_key(input) {
+                Key::Char(b'\r') | Key::Char(b'\n') => {
+                    println!();
+                    let command = String::from(line.trim());
+                    if !command.is_empty() && self.history.last() != Some(&command) {
+                        if self.history.len() == HISTORY_SIZE {
+                            self.history.remove(0);
+                        }
+                        self.history.push(command.clone());
+                    }
+                    return Some(command);
+                }
+                Key::Char(0x03) => {
+                    println!("^C");
+                    return None;
+                }
+                Key::Char(0x15) => {
+                    line.clear();
+                    redraw(&line);
+                }
+                Key::Char(0x08) | Key::Char(0x7f) => {
+                    if line.pop().is_some() {
+                        print!("\x08 \x08");
+                    }
+                }
+                Key::Char(c) if (0x20..0x7f).contains(&c) && line.len() < LINE_SIZE => {
+                    line.push(c as char);
+                    print!("{}", c as char);
+                }
+                Key::Up if position > 0 => {
+                    position -= 1;
+                    line = self.history[position].clone();
+                    redraw(&line);
+                }
+                Key::Down if position < self.history.len() => {
+                    position += 1;
+                    line = self.history.get(position).cloned().unwrap_or_default();
+                    redraw(&line);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn help() {
+    println!("help                          this list");
+    println!("regs                          system registers of the current EL");
+    println!("vobj                          CPU description for the emulator");
+    println!("md <addr> [len]               hexdump");
+    println!("peek <addr> [1|2|4|8]         read a value");
+    println!("poke <addr> <value> [1|2|4|8] write a value");
+    println!("sym <addr>                    symbol containing an address");
+    println!("dt [path]                     device tree nodes or node properties");
+    println!("pt                            page tables");
+    println!("heap                          heap report");
+    println!("frames                        free physical frames");
+    println!("bench                         CPU benchmark");
+    println!("load <file> [addr]            load a host file (semihosting)");
+    println!("run                           run the payload");
+    println!("reset                         reset the platform");
+    println!("exit [code]                   leave the monitor");
+}
+
+macro_rules! print_sysreg {
+    ($name:literal) => {{
+        let value: u64;
+        unsafe { asm!(concat!("mrs {}, ", $name), out(reg) value) };
+        println!("{:<18}{:#018x}", $name, value);
+    }};
+}
+
+fn regs() {
+    let current_el = processor::get_current_el();
+    println!("{:<18}{}", "CurrentEL", current_el);
+    print_sysreg!("MIDR_EL1");
+    print_sysreg!("MPIDR_EL1");
+    print_sysreg!("ID_AA64PFR0_EL1");
+    print_sysreg!("ID_AA64ISAR0_EL1");
+    print_sysreg!("ID_AA64MMFR0_EL1");
+    print_sysreg!("ID_AA64DFR0_EL1");
+    print_sysreg!("CNTFRQ_EL0");
+    print_sysreg!("CNTVCT_EL0");
+    match current_el {
+        1 => {
+            print_sysreg!("SCTLR_EL1");
+            print_sysreg!("TTBR0_EL1");
+            print_sysreg!("MAIR_EL1");
+        }
+        2 => {
+            print_sysreg!("SCTLR_EL2");
+            print_sysreg!("TTBR0_EL2");
+            print_sysreg!("MAIR_EL2");
+            print_sysreg!("HCR_EL2");
+        }
+        3 => {
+            print_sysreg!("SCTLR_EL3");
+            print_sysreg!("TTBR0_EL3");
+            print_sysreg!("MAIR_EL3");
+            print_sysreg!("SCR_EL3");
+        }
+        _ => {}
+    }
+    println!("{:<18}{:#018x}", "TCR", processor::get_tcr());
+    println!("{:<18}{:#018x}", "VBAR", processor::get_vbar());
+}
+
+fn hexdump(address: u64, length: u64) {
+    // the dump stops at the top of the address space
+    let end = address.saturating_add(length);
+    let mut line = address & !0xf;
+    while line < end {
+        print!("{:#012x}: ", line);
+        let mut ascii = [b' '; 16];
+        for i in 0..16 {
+            let at = line + i;
+            if at < address || at >= end {
+                print!("   ");
+                continue;
+            }
+            let byte = unsafe { ptr::read_volatile(at as *const u8) };
+            print!("{:02x} ", byte);
+            ascii[i as usize] = if (0x20..0x7f).contains(&byte) { byte } else { b'.' };
+        }
+        println!(" {}", core::str::from_utf8(&ascii).unwrap_or(""));
+        line = match line.checked_add(16) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+}
+
+fn is_valid_access(address: u64, width: u64) -> bool {
+    matches!(width, 1 | 2 | 4 | 8) && address & (width - 1) == 0
+}
+
+fn peek(address: u64, width: u64) -> Option<u64> {
+    if !is_valid_access(address, width) {
+        return None;
+    }
+    let value = unsafe {
+        match width {
+            1 => ptr::read_volatile(address as *const u8) as u64,
+            2 => ptr::read_volatile(address as *const u16) as u64,
+            4 => ptr::read_volatile(address as *const u32) as u64,
+            8 => ptr::read_volatile(address as *const u64),
+            _ => return None,
+        }
+    };
+    Some(value)
+}
+
+fn poke(address: u64, value: u64, width: u64) -> bool {
+    if !is_valid_access(address, width) {
+        return false;
+    }
+    unsafe {
+        match width {
+            1 => ptr::write_volatile(address as *mut u8, value as u8),
+            2 => ptr::write_volatile(address as *mut u16, value as u16),
+            4 => ptr::write_volatile(address as *mut u32, value as u32),
+            8 => ptr::write_volatile(address as *mut u64, value),
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn print_prop_value(raw: &[u8]) {
+    // strings (or string lists) are printed as such, anything else as cells
+    let printable = !raw.is_empty() && raw[raw.len() - 1] == 0
+        && raw.iter().all(|&b| b == 0 || (0x20..0x7f).contains(&b))
+        && raw[0] != 0;
+    if printable {
+        let text = core::str::from_utf8(&raw[..raw.len() - 1]).unwrap_or("");
+        let mut first = true;
+        for s in text.split('\0') {
+            print!("{}\"{}\"", if first { "" } else { ", " }, s);
+            first = false;
+        }
+    }
+    else if raw.len() % 4 == 0 {
+        print!("<");
+        for (i, cell) in raw.chunks_exact(4).enumerate() {
+            let cell = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
+            print!("{}{:#x}", if i == 0 { "" } else { " " }, cell);
+        }
+        print!(">");
+    }
+    else {
+        print!("[");
+        for (i, byte) in raw.iter().enumerate() {
+            print!("{}{:02x}", if i == 0 { "" } else { " " }, byte);
+        }
+        print!("]");
+    }
+}
+
+fn devicetree(devt: Option<&DeviceTree>, path: Option<&str>) {
+    let devt = match devt {
+        Some(devt) => devt,
+        None => {
+            println!("no device tree");
+            return;
+        }
+    };
+    match path {
+        None => {
+            for node in devt.get_nodes() {
+                println!("{}", dt::to_path(&node));
+            }
+        }
+        Some(path) => {
+            let node = match devt.get_node_by_path(path) {
+                Some(node) => node,
+                None => {
+                    println!("{}: not found", path);
+                    return;
+                }
+            };
+            println!("{} {{", path);
+            for prop in node.props() {
+                print!("    {}", prop.name().unwrap_or("?"));
+                if prop.length() > 0 {
+                    print!(" = ");
+                    print_prop_value(prop.raw());
+                }
+                println!(";");
+            }
+            for child in node.children() {
+                println!("    {} {{ ... }};", child.name().unwrap_or("?"));
+            }
+            println!("}};");
+        }
+    }
+}
+
+/* loads at `address`, or in newly allocated frames when it is 0 */
+fn load(platform: &Box<dyn PlatformOperations>, path: &str, address: u64) {
+    let size = match HostFile::open(path).and_then(|file| file.len()) {
+        Some(size) => size,
+        None => {
+            println!("{}: can't open on the host", path);
+            return;
+        }
+    };
+    let pages = size.div_ceil(frames::PAGE_SIZE as usize);
+    let allocated = address == 0;
+    let address = if !allocated {
+        address
+    }
+    else {
+        match platform.allocate_frames(pages, 0) {
+            Some(address) => address,
+            None => {
+                println!("no memory for {} bytes", size);
+                return;
+            }
+        }
+    };
+    match semihosting::load_file(path, address, size) {
+        Some(size) => println!("{}: {} bytes at {:#x}", path, size, address),
+        None => {
+            println!("{}: read failed", path);
+            if allocated {
+                platform.free_frames(address, pages);
+            }
+        }
+    }
+}
+
+fn number(argument: Option<&str>, default: Option<u64>) -> Option<u64> {
+    match argument {
+        Some(text) => parse_u64(text),
+        None => default,
+    }
+}
+
+/// Runs the monitor until `exit`, returning the exit code (or the result of `run`).
+pub fn monitor(platform: &Box<dyn PlatformOperations>, devt: Option<&DeviceTree>) -> i64 {
+    let input = match log::input() {
+        Some(input) => input,
+        None => {
+            warn!("the console has no input, running the payload instead of the monitor");
+            return run::run(platform);
+        }
+    };
+
+    println!("barekit monitor, type help for the command list");
+    let mut editor = LineEditor::new();
+    loop {
+        let line = match editor.read_line(input) {
+            Some(line) => line,
+            None => continue,
+        };
+        let mut words = line.split_ascii_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue,
+        };
+        let arg1 = words.next();
+        let arg2 = words.next();
+        let arg3 = words.next();
+
+        match command {
+            "help" | "?" => help(),
+            "regs" => regs(),
+            "vobj" => run::generate_cpu_vobj(),
+            "md" | "hexdump" => match (number(arg1, None), number(arg2, Some(DEFAULT_DUMP_SIZE))) {
+                (Some(address), Some(length)) => hexdump(address, length),
+                _ => println!("usage: md <addr> [len]"),
+            },
+            "peek" => match (number(arg1, None), number(arg2, Some(8))) {
+                (Some(address), Some(width)) => match peek(address, width) {
+                    Some(value) => println!("{:#x}: {:#x}", address, value),
+                    None => println!("invalid width or unaligned address"),
+                },
+                _ => println!("usage: peek <addr> [1|2|4|8]"),
+            },
+            "poke" => match (number(arg1, None), number(arg2, None), number(arg3, Some(8))) {
+                (Some(address), Some(value), Some(width)) => {
+                    if !poke(address, value, width) {
+                        println!("invalid width or unaligned address");
+                    }
+                }
+                _ => println!("usage: poke <addr> <value> [1|2|4|8]"),
+            },
+            "sym" => match number(arg1, None) {
+                Some(address) => match symbols::symbolize(address) {
+                    Some((name, offset)) => println!("{:#x}: {}+{:#x}", address, name, offset),
+                    None if symbols::count() == 0 => println!("no symbol table in the image"),
+                    None => println!("{:#x}: no symbol", address),
+                },
+                None => println!("usage: sym <addr>"),
+            },
+            "dt" => devicetree(devt, arg1),
+            "pt" => run::dump_paging(),
+            "heap" => heap::heap_report().print(),
+            "frames" => frames::dump(),
+            "bench" => run::cpu_burn(),
+            "load" => match (arg1, number(arg2, Some(0))) {
+                (Some(path), Some(address)) => load(platform, path, address),
+                _ => println!("usage: load <file> [addr]"),
+            },
+            "run" => {
+                let result = run::run(platform);
+                println!("run returned {}", result);
+            }
+            "reset" => {
+                platform.reset();
+                // only back here when the platform has no way to reset
+                println!("reset is not supported on this platform");
+            }
+            "exit" | "return" => match number(arg1, Some(0)) {
+                Some(code) => return code as i64,
+                None => println!("usage: exit [code]"),
+            },
+            _ => println!("{}: unknown command, type help for the list", command),
+        }
+    }
+}
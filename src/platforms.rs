@@ -6,7 +6,6 @@
 
 use alloc::boxed::Box;
 use alloc::string::String;
-use alloc::vec::Vec;
 use core::hint;
 
 pub mod el_1_2;
@@ -16,12 +15,17 @@ pub mod efi;
 
 use crate::RuntimeContext;
 use crate::println;
-use crate::ALLOC_SIZE;
-use crate::ALLOC_COUNT;
+use crate::frames;
+use crate::semihosting;
+use crate::drivers::psci;
+use crate::heap;
+use crate::heap::OomPolicy;
 use crate::early_prints;
 
 use crate::platforms;
 use crate::dt::DeviceTree;
+use crate::drivers::{Framebuffer, FramebufferConsole, SIMPLE_FRAMEBUFFER};
+use crate::log::Logger;
 
 use fdt_rs::index::DevTreeIndexNode;
 use fdt_rs::prelude::PropReader;
@@ -46,6 +50,24 @@ pub struct PlatformInfo {
 
 pub struct Platform {}
 
+// the platform owned by rrt1_entry, for code that can't be handed a reference (OOM, panic)
+static mut CURRENT: Option<*const dyn PlatformOperations<'static>> = None;
+
+pub fn set_current(platform: &Box<dyn PlatformOperations<'static>>) {
+    unsafe {
+        CURRENT = Some(&**platform as *const dyn PlatformOperations<'static>);
+    }
+}
+
+pub fn current() -> Option<&'static dyn PlatformOperations<'static>> {
+    unsafe {
+        match CURRENT {
+            Some(platform) => Some(&*platform),
+            None => None
+        }
+    }
+}
+
 impl Platform {
 
     pub fn new_from(information: PlatformInfo) -> Box<dyn PlatformOperations<'static>> {
@@ -61,10 +83,11 @@ impl Platform {
         platform
     }
 
-    pub fn get_stdout<'a>(devt: &'a Box<DeviceTree<'a>>, base: &'a str) -> Option<DevTreeIndexNode<'a, 'a, 'a>> {
+    /// The stdout-path node and its options, the part after ':' such as "115200n8".
+    pub fn get_stdout<'a>(devt: &'a Box<DeviceTree<'a>>, base: &'a str) -> Option<(DevTreeIndexNode<'a, 'a, 'a>, Option<&'a str>)> {
         // now setup the console
         early_prints!("console stuff\n",0);
-        let chosen_node= devt.get_node_by_name(base).unwrap();
+        let chosen_node= devt.get_node_by_name(base)?;
         early_prints!("chosen\n",0);
         let stdout_prop = devt.get_prop_by_name(&chosen_node, "stdout-path");
         let mut stdout = "serial0"; // lets have a default... (needed for RPI4 ;-)
@@ -77,10 +100,11 @@ impl Platform {
         else {
             early_prints!("stdout-path is not set, trying default=serial0\n",0);
         }
-        let full_path = String::from(stdout);
-        let s = full_path.split(":");
-        let vec: Vec<&str> = s.collect();
-        devt.get_node_by_path(vec[0])
+        let (path, options) = match stdout.split_once(':') {
+            Some((path, options)) => (path, Some(options)),
+            None => (stdout, None)
+        };
+        Some((devt.get_node_by_path(path)?, options))
     }
 
 }
@@ -90,23 +114,49 @@ pub trait PlatformOperations<'a> {
     fn get_fdt_address(&self) -> Option<u64>;
 
     fn pre_stop(&self) {
-        unsafe {
-            println!("BumpAllocator stats: {} allocations, {} bytes.", ALLOC_COUNT, ALLOC_SIZE);
-        }
+        heap::heap_report().print();
+        #[cfg(feature = "heap_debug")]
+        crate::heap_debug::heap_check();
     }
 
-    fn stop(&self) {
-
+    /// Called with the result of run(): ends the simulation when semihosting is enabled.
+    fn stop(&self, status: i64) {
+        semihosting::exit(status);
     }
 
+    /// Resets the board through PSCI, returns only when that is not possible.
     fn reset(&self) {
+        psci::system_reset();
+    }
+
+    /// True when reset can work, oom=reset is refused otherwise.
+    fn can_reset(&self) -> bool {
+        psci::available()
+    }
+
+    /// Gives control back to the loader with `status`, when the loader allows it.
+    fn exit(&self, status: i64) {
+        semihosting::exit(status);
+    }
+
+    fn oom_policy(&self) -> OomPolicy {
+        OomPolicy::Halt
+    }
 
+    fn get_cmdline(&self) -> Option<String> {
+        None
     }
 
     fn set_boot_tty(&mut self) {
 
     }
 
+    /// Removes the sink installed by set_boot_tty once the device tree console
+    /// is up, returns true if it was active.
+    fn unregister_boot_tty(&mut self) -> bool {
+        false
+    }
+
     fn can_return(&self) -> bool {
         return false;
     }
@@ -136,4 +186,37 @@ pub trait PlatformOperations<'a> {
         return false;
     }
 
+    /// Allocates `pages` contiguous physical pages aligned on `align` bytes (0 for page alignment).
+    fn allocate_frames(&self, pages: usize, align: u64) -> Option<u64> {
+        frames::allocate(pages, align)
+    }
+
+    /// Allocates `pages` physical pages starting exactly at `address`.
+    fn allocate_frames_at(&self, address: u64, pages: usize) -> Option<u64> {
+        frames::allocate_at(address, pages)
+    }
+
+    fn free_frames(&self, address: u64, pages: usize) {
+        frames::free(address, pages);
+    }
+
+    /// Withdraws a physical range (firmware tables, loaded payloads...) from the free frames.
+    fn reserve_frames(&self, address: u64, size: u64) {
+        frames::reserve(address, size);
+    }
+
+    /// The framebuffer set up by the firmware, if any.
+    fn get_framebuffer(&self) -> Option<Framebuffer> {
+        None
+    }
+
+    /// A console outside the device tree for console=<driver name>, such as the
+    /// framebuffer the firmware set up.
+    fn get_console(&self, driver: &str) -> Option<Box<dyn Logger>> {
+        if driver != SIMPLE_FRAMEBUFFER {
+            return None;
+        }
+        self.get_framebuffer().and_then(FramebufferConsole::new)
+    }
+
 }
@@ -7,20 +7,32 @@
 use alloc::boxed::Box;
 use r_efi::efi::Status;
 use r_efi::efi::RESET_COLD;
+use r_efi::efi::PhysicalAddress;
+use r_efi::system::{ALLOCATE_ANY_PAGES, ALLOCATE_ADDRESS, LOADER_DATA};
+use r_efi::system::{CONVENTIONAL_MEMORY, BOOT_SERVICES_CODE, BOOT_SERVICES_DATA, MemoryDescriptor};
 
 use crate::PlatformOperations;
 use crate::PlatformInfo;
-use crate::dt::DeviceTree;
+use crate::dt::{DeviceTree, Region};
+
+use alloc::string::String;
+use alloc::vec::Vec;
 
 use crate::drivers::TTYEFI;
+use crate::drivers::{Framebuffer, PixelFormat};
+use crate::frames;
+use crate::heap;
+use crate::heap::OomPolicy;
 use crate::log;
-use crate::println;
+use crate::log::Level;
+use crate::earlycon;
+use crate::early_prints;
 
 use r_efi::efi;
 
 
-//#[cfg(feature = "early_print")]
-//use crate::print::_early_print_s;
+#[cfg(feature = "early_print")]
+use crate::print::_early_print_s;
 
 pub const FDT_TABLE_GUID: r_efi::efi::Guid = r_efi::efi::Guid::from_fields(
     0xb1b621d5,
@@ -31,13 +43,64 @@ pub const FDT_TABLE_GUID: r_efi::efi::Guid = r_efi::efi::Guid::from_fields(
     &[0xd9,0x15,0x2c,0x69,0xaa,0xe0],
 );
 
+// the following values can't be changed as they are defined by EFI standard
+const EFI_PAGE_SIZE : usize = 4096;
+
+// valid until taking_over: afterwards the heap is frozen
+static mut BOOT_SERVICES: *const efi::BootServices = core::ptr::null();
+
+fn allocate_heap_pages(boot_services: *const efi::BootServices, size: usize) -> Option<(usize, usize)> {
+    let pages = size.div_ceil(EFI_PAGE_SIZE);
+    let mut base : PhysicalAddress = 0;
+    let r = unsafe {
+        ((*boot_services).allocate_pages)(ALLOCATE_ANY_PAGES, LOADER_DATA, pages, &mut base)
+    };
+    if r.is_error() {
+        return None;
+    }
+    Some((base as usize, pages * EFI_PAGE_SIZE))
+}
+
+/* HeapGrowFn used while boot services are available */
+fn heap_grow(size: usize) -> Option<(usize, usize)> {
+    let boot_services = unsafe { BOOT_SERVICES };
+    if boot_services.is_null() {
+        return None;
+    }
+    allocate_heap_pages(boot_services, core::cmp::max(size, frames::HEAP_GROW_MIN))
+}
+
+fn boot_services() -> Option<&'static efi::BootServices> {
+    unsafe { BOOT_SERVICES.as_ref() }
+}
+
+/// Allocates the boot HEAP through boot services and lets the heap grow on demand.
+/// Tries `preferred` bytes first and falls back to smaller chunks when memory is tight.
+pub fn setup_heap(boot_services: *const efi::BootServices, preferred: usize) -> Option<(usize, usize)> {
+    let mut size = preferred;
+    loop {
+        if let Some(region) = allocate_heap_pages(boot_services, size) {
+            unsafe {
+                BOOT_SERVICES = boot_services;
+            }
+            heap::heap_set_grow(Some(heap_grow));
+            return Some(region);
+        }
+        if size <= EFI_PAGE_SIZE {
+            return None;
+        }
+        size /= 2;
+    }
+}
 
 pub struct Platform<'a> {
     _image_handle:          efi::Handle,
     sys_tab:        *const efi::SystemTable,
     information:    PlatformInfo,
     _dt:            Option<Box<DeviceTree<'a>>>,
-    _state:          EFI_ServicesState
+    _state:          EFI_ServicesState,
+    // ConOut is mirrored next to the console until boot services are exited
+    conout_sink:    Option<log::SinkId>,
 }
 
 enum EFI_ServicesState {
@@ -52,7 +115,7 @@ impl<'a> Platform<'a>  {
         let sys_tab: &efi::SystemTable = unsafe { &*(information.x1_at_startup as * mut efi::SystemTable)};
         let image_handle = information.x0_at_startup  as efi::Handle;
         let state = EFI_ServicesState::BootServicesAvailable;
-        Self { _image_handle: image_handle, sys_tab, information, _dt: None , _state: state }
+        Self { _image_handle: image_handle, sys_tab, information, _dt: None , _state: state, conout_sink: None }
     }
     
 }
@@ -78,8 +141,23 @@ impl<'a> PlatformOperations<'a> for Platform<'a> {
         let tty = TTYEFI{sys_tab: st};
         //early_prints!("about to set tty_earlydev, TTYEFI\n", 0);
         let s = log::get_unprinted();
-        log::set_target(Some(Box::new(tty)));
-        println!("{}", &s);
+        // ConOut is usually the serial port the early console writes to: it takes over
+        let shown = earlycon::unregister();
+        // a sink of its own, removed when the UART of the device tree becomes the console
+        self.conout_sink = log::add_sink(Box::new(tty), Level::Trace);
+        if let (Some(id), false) = (self.conout_sink, shown) {
+            log::write_fmt_to(id, format_args!("{}\n", &s));
+        }
+    }
+
+    fn unregister_boot_tty(&mut self) -> bool {
+        match self.conout_sink.take() {
+            Some(id) => {
+                log::remove_sink(id);
+                true
+            }
+            None => false,
+        }
     }
 
     fn get_info(&self) -> &PlatformInfo {
@@ -88,8 +166,120 @@ impl<'a> PlatformOperations<'a> for Platform<'a> {
 
     fn taking_over(&self, info: u64) {
         let st = unsafe {&*(self.sys_tab)};
+        // pages already obtained stay LOADER_DATA: the heap becomes a static arena
+        heap::heap_set_grow(None);
+        let boot_services = unsafe { &*(st.boot_services) };
+        // boot services data holds the stack we run on and often the FDT
+        let mut holes: Vec<Region> = Vec::new();
+        holes.push(Region {
+            base: self.information.boot_stack_top - self.information.boot_stack_capacity as u64,
+            size: self.information.boot_stack_capacity as u64
+        });
+        if let Some(fdt) = self.get_fdt_address() {
+            // totalsize, big endian, follows the magic
+            let size = u32::from_be(unsafe { core::ptr::read_unaligned((fdt + 4) as *const u32) });
+            holes.push(Region { base: fdt, size: size as u64 });
+        }
         unsafe {
-            ((*(st.boot_services)).exit_boot_services)(self._image_handle, info as usize);
+            BOOT_SERVICES = core::ptr::null();
+        }
+        if let Some(id) = self.conout_sink {
+            log::remove_sink(id);
+        }
+        // the key passed by the caller is stale as soon as the memory map is read again,
+        // and the map changes if the firmware handles an event in between: retry with a new key
+        let mut map_key = info as usize;
+        let mut seeded = false;
+        for _ in 0..4 {
+            let mut map_size: usize = 0;
+            let mut descriptor_size: usize = 0;
+            let mut descriptor_version: u32 = 0;
+            (boot_services.get_memory_map)(&mut map_size, core::ptr::null_mut(), &mut map_key, &mut descriptor_size, &mut descriptor_version);
+            // the map may grow with our own allocation
+            map_size += 4 * descriptor_size;
+            let mut map: Vec<u64> = Vec::with_capacity(map_size / 8 + 1);
+            let r = (boot_services.get_memory_map)(&mut map_size, map.as_mut_ptr() as *mut MemoryDescriptor, &mut map_key, &mut descriptor_size, &mut descriptor_version);
+            // the frames get the memory that becomes free once boot services are gone, minus the holes
+            if !seeded && !r.is_error() && descriptor_size != 0 {
+                let base = map.as_ptr() as usize;
+                for offset in (0..map_size).step_by(descriptor_size) {
+                    let descriptor = unsafe { core::ptr::read_unaligned((base + offset) as *const MemoryDescriptor) };
+                    match descriptor.r#type {
+                        CONVENTIONAL_MEMORY | BOOT_SERVICES_CODE | BOOT_SERVICES_DATA => {
+                            frames::add_range(descriptor.physical_start, descriptor.number_of_pages * EFI_PAGE_SIZE as u64);
+                        }
+                        _ => {}
+                    }
+                }
+                for hole in &holes {
+                    frames::reserve(hole.base, hole.size);
+                }
+                seeded = true;
+            }
+            let r = (boot_services.exit_boot_services)(self._image_handle, map_key);
+            if r != Status::INVALID_PARAMETER {
+                if r.is_error() {
+                    early_prints!("exit_boot_services failed: %\n", r.as_usize() as u64);
+                }
+                return;
+            }
+        }
+        early_prints!("exit_boot_services: memory map keeps changing\n", 0);
+    }
+
+    fn allocate_frames(&self, pages: usize, align: u64) -> Option<u64> {
+        let boot_services = match boot_services() {
+            Some(bs) => bs,
+            None => return frames::allocate(pages, align),
+        };
+        // over-allocate to honour the alignment and give back the unused pages
+        let align_pages = if align > EFI_PAGE_SIZE as u64 { (align / EFI_PAGE_SIZE as u64) as usize - 1 } else { 0 };
+        let mut base : PhysicalAddress = 0;
+        let r = (boot_services.allocate_pages)(ALLOCATE_ANY_PAGES, LOADER_DATA, pages + align_pages, &mut base);
+        if r.is_error() {
+            return None;
+        }
+        let aligned = heap::align_up(base as usize, core::cmp::max(align as usize, EFI_PAGE_SIZE)) as u64;
+        let head = ((aligned - base) / EFI_PAGE_SIZE as u64) as usize;
+        if head != 0 {
+            (boot_services.free_pages)(base, head);
+        }
+        if align_pages > head {
+            (boot_services.free_pages)(aligned + (pages * EFI_PAGE_SIZE) as u64, align_pages - head);
+        }
+        Some(aligned)
+    }
+
+    fn allocate_frames_at(&self, address: u64, pages: usize) -> Option<u64> {
+        let boot_services = match boot_services() {
+            Some(bs) => bs,
+            None => return frames::allocate_at(address, pages),
+        };
+        let mut base : PhysicalAddress = address;
+        let r = (boot_services.allocate_pages)(ALLOCATE_ADDRESS, LOADER_DATA, pages, &mut base);
+        if r.is_error() {
+            return None;
+        }
+        Some(base)
+    }
+
+    fn free_frames(&self, address: u64, pages: usize) {
+        match boot_services() {
+            Some(bs) => { (bs.free_pages)(address, pages); },
+            None => { frames::free(address, pages); }
+        }
+    }
+
+    fn reserve_frames(&self, address: u64, size: u64) {
+        // while boot services run, the firmware memory map is the reference:
+        // the pages are taken from it, a failure means the firmware already owns them
+        match boot_services() {
+            Some(_) => {
+                let base = address & !(EFI_PAGE_SIZE as u64 - 1);
+                let pages = ((address + size - base) as usize).div_ceil(EFI_PAGE_SIZE);
+                let _ = self.allocate_frames_at(base, pages);
+            }
+            None => { frames::reserve(address, size); }
         }
     }
 
@@ -108,6 +298,73 @@ impl<'a> PlatformOperations<'a> for Platform<'a> {
         "EFI"
     }
 
+    fn exit(&self, status: i64) {
+        if let Some(boot_services) = boot_services() {
+            let status = if status == 0 { Status::SUCCESS } else { Status::OUT_OF_RESOURCES };
+            (boot_services.exit)(self._image_handle, status, 0, core::ptr::null_mut());
+        }
+    }
+
+    fn oom_policy(&self) -> OomPolicy {
+        if self.can_return() { OomPolicy::Exit } else { OomPolicy::Halt }
+    }
+
+    fn get_cmdline(&self) -> Option<String> {
+        let st = unsafe {&*(self.sys_tab)};
+        let mut pimage: *mut core::ffi::c_void = core::ptr::null_mut();
+        let mut guid = efi::protocols::loaded_image::PROTOCOL_GUID;
+        let r = unsafe {
+            ((*st.boot_services).handle_protocol)(self._image_handle, &mut guid, &mut pimage)
+        };
+        if r.is_error() || pimage.is_null() {
+            return None;
+        }
+        let loaded_image = unsafe { &*(pimage as *const efi::protocols::loaded_image::Protocol) };
+        if loaded_image.load_options.is_null() || loaded_image.load_options_size < 2 {
+            return None;
+        }
+        // load options are UCS-2, possibly NUL terminated
+        let options = unsafe {
+            core::slice::from_raw_parts(loaded_image.load_options as *const u16, loaded_image.load_options_size as usize / 2)
+        };
+        let options = options.split(|&c| c == 0).next().unwrap();
+        let line: String = char::decode_utf16(options.iter().cloned())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some(line)
+    }
+
+    fn get_framebuffer(&self) -> Option<Framebuffer> {
+        let boot_services = boot_services()?;
+        let mut guid = efi::protocols::graphics_output::PROTOCOL_GUID;
+        let mut pgop: *mut core::ffi::c_void = core::ptr::null_mut();
+        let r = (boot_services.locate_protocol)(&mut guid, core::ptr::null_mut(), &mut pgop);
+        if r.is_error() || pgop.is_null() {
+            return None;
+        }
+        let gop = unsafe { &*(pgop as *const efi::protocols::graphics_output::Protocol) };
+        let mode = unsafe { &*gop.mode };
+        let info = unsafe { &*mode.info };
+        use efi::protocols::graphics_output::{PIXEL_RED_GREEN_BLUE_RESERVED_8_BIT_PER_COLOR, PIXEL_BLUE_GREEN_RED_RESERVED_8_BIT_PER_COLOR};
+        // bit mask formats and BLT only devices are not handled
+        let format = match info.pixel_format {
+            PIXEL_RED_GREEN_BLUE_RESERVED_8_BIT_PER_COLOR => PixelFormat::Xbgr8888,
+            PIXEL_BLUE_GREEN_RED_RESERVED_8_BIT_PER_COLOR => PixelFormat::Xrgb8888,
+            _ => return None,
+        };
+        Some(Framebuffer {
+            base: mode.frame_buffer_base,
+            width: info.horizontal_resolution as usize,
+            height: info.vertical_resolution as usize,
+            stride: info.pixels_per_scan_line as usize * 4,
+            format,
+        })
+    }
+
+    fn can_reset(&self) -> bool {
+        true
+    }
+
     fn reset(&self) {
         let st = unsafe {&*(self.sys_tab)};
         unsafe {
@@ -17,7 +17,6 @@ use crate::drivers::ns16550a::NS16550Output;
 use crate::early_prints;
 
 use crate::log;
-use crate::println;
 #[cfg(feature = "early_print")]
 use crate::print::_early_print_s;
 
@@ -107,11 +106,11 @@ impl<'a> PlatformOperations<'a> for Platform<'a> {
 
     fn set_boot_tty(&mut self) {
         if self.fdt_address == 0 {
-            let tty = NS16550Output::from_mmio(drivers::DESIGNWARE , 0xf051_2000,  1, 2);
-            //let tty = PL011Output::from_mmio(drivers::PL011 , 0x0900_0000,  0, 0);
             let s = log::get_unprinted();
-            log::set_target(tty);
-            println!("{}", &s);
+            NS16550Output::register_static(drivers::DESIGNWARE , 0xf051_2000,  1, 2);
+            //log::set_target(PL011Output::from_mmio(drivers::PL011 , 0x0900_0000,  0, 0));
+            // the boot buffer stays a sink: only the new console misses its text
+            log::write_fmt_to(log::CONSOLE_SINK, format_args!("{}\n", &s));
             }
     }
 
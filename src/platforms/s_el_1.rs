@@ -43,7 +43,7 @@ impl<'a> Platform<'a>  {
 
 impl<'a> PlatformOperations<'a> for Platform<'a> {
 
-    fn stop(&self) {
+    fn stop(&self, _status: i64) {
         let tlk_entry_done: u64 = 0x32000003 | (1 << 31);
         // signals tlk_entry_done to tlkd in EL3
         // https://elixir.bootlin.com/arm-trusted-firmware/latest/source/services/spd/tlkd/tlkd_main.c#L405
@@ -9,9 +9,7 @@ use crate::{log};
 use core::fmt;
 
 #[cfg(feature = "early_print")]
-use core::hint;
-#[cfg(feature = "early_print")]
-use core::arch::asm;
+use crate::earlycon;
 
 //--------------------------------------------------------------------------------------------------
 // Public Code
@@ -19,62 +17,16 @@ use core::arch::asm;
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    let tty = log::tty();
-    tty.as_mut().unwrap().write_fmt(args).unwrap();
+    // nothing to print to yet: the output is lost rather than panicking
+    log::write_fmt(None, args);
 }
 
-#[allow(dead_code)]
-static mut PL011_QEMU:  *mut u8 = 0x0900_0000 as *mut u8;
-#[allow(dead_code)]
-static mut NS16550_LKVM:  *mut u8 = 0x100_0000 as *mut u8;
-#[allow(dead_code)]
-static mut NS16550_RPI4:  *mut u32 = 0xfe21_5040 as *mut u32;
-#[allow(dead_code)]
-static mut PL011_HONEYCOMB:  *mut u32 = 0x21c_0000 as *mut u32;
-#[allow(dead_code)]
-static mut PL011_SYNQUACER:  *mut u32 = 0x2a40_0000 as *mut u32;
-#[allow(dead_code)]
-static mut NS6550_MACCHIATOBIN:  *mut u8 = 0xf051_2000 as *mut u8;
-
-#[allow(dead_code)]
-//SETUP: choose default serial, look for SETUP to know all places to change values
-//static mut RRT0_PORT: *mut u32 = 0xfe21_5040 as *mut u32;
-//static mut RRT0_PORT: *mut u32 = 0x21c_0000 as *mut u32;
-static mut RRT0_PORT: *mut u8 = 0x0900_0000 as *mut u8;
-//static mut RRT0_PORT: *mut u8 = 0x100_0000 as *mut u8;
-// Solidrun Macchiatobin
-//static mut RRT0_PORT: *mut u8 = 0xf051_2000 as *mut u8;
-
 #[doc(hidden)]
 #[allow(dead_code)]
 #[cfg(feature = "early_print")]
 pub fn _early_putc(c: char) {
-    unsafe {
-        asm!(
-            "str {value:w}, [{port}]",
-            port = in(reg) (RRT0_PORT as *mut u32),
-            value = in(reg) (c as u32),
-            options(nostack, preserves_flags)
-        );
-    }
-    // bad hack to avoid overloading real HW... 
-    // real driver are polling for an appropriate time to send chars...
-    for _i in 0..9000 {
-        hint::spin_loop();
-    }
-    if c == '\n' {
-        unsafe {
-            asm!(
-                "str {value:w}, [{port}]",
-                port = in(reg) (RRT0_PORT as *mut u32),
-                value = in(reg) ('\r' as u32),
-                options(nostack, preserves_flags)
-            );
-        }
-        for _i in 0..9000 {
-            hint::spin_loop();
-        }
-    }
+    // the early console polls the UART, see earlycon.rs to choose it
+    earlycon::putc(c as u8);
 }
 
 #[doc(hidden)]
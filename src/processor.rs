@@ -121,6 +121,20 @@ pub fn get_elr() -> u64 {
     return value;
 }
 
+pub fn get_far() -> u64 {
+    let current_el = get_current_el();
+    let value : u64;
+    unsafe {
+        match  current_el {
+            1 => asm!("mrs {}, FAR_EL1", out(reg) value),
+            2 => asm!("mrs {}, FAR_EL2", out(reg) value),
+            3 => asm!("mrs {}, FAR_EL3", out(reg) value),
+            _ => panic!("Invalid EL retrieved: {:#x}", current_el)
+        }
+    };
+    return value;
+}
+
 pub fn get_tcr() -> u64 {
     let current_el = get_current_el();
     let value : u64;
@@ -17,12 +17,10 @@ use core::arch::global_asm;
 
 use alloc::boxed::Box;
 
-use r_efi::system::{ALLOCATE_ANY_PAGES, LOADER_DATA};
-use r_efi::efi::{self, PhysicalAddress};
+use r_efi::efi;
 use crate::pe::{DosHeader, NtHeader};
 
 use crate::drivers::TTYBuffer;
-use crate::heap::{ALLOC_SIZE, ALLOC_COUNT};
 use crate::platforms::{Platform, PlatformInfo, PlatformOperations};
 use crate::rrt1::rrt1_entry;
 
@@ -30,13 +28,23 @@ use crate::rrt1::rrt1_entry;
 use crate::print::_early_print_s;
 
 mod drivers;
+mod cmdline;
 mod log;
 mod print;
 mod heap;
+#[cfg(feature = "heap_debug")]
+mod heap_debug;
+mod frames;
 mod rrt1;
 mod dt;
 mod platforms;
 mod run;
+mod monitor;
+mod backtrace;
+mod exception;
+mod symbols;
+mod semihosting;
+mod earlycon;
 mod coff_stager;
 mod processor;
 mod pe;
@@ -148,6 +156,7 @@ pub extern "C"  fn rrt0_entry(x0: u64, x1: u64, x2: u64, x3: u64, x4: u64, x5: u
     let load_address: u64;
     let mut end_of_image: u64;
     let start_of_heap: u64;
+    let mut heap_size: usize = BOOT_HEAP_SIZE;
     let mut end_of_stack: u64;
     
     if rc == RuntimeContext::EFI {
@@ -165,17 +174,17 @@ pub extern "C"  fn rrt0_entry(x0: u64, x1: u64, x2: u64, x3: u64, x4: u64, x5: u
             load_address = (*loaded_image).image_base as u64;
             end_of_image = (*loaded_image).image_size as u64 + load_address;
             end_of_image = (end_of_image + (EFI_PAGE_SIZE - 1)) & !EFI_PAGE_MASK;
-            let mut heapbase : PhysicalAddress = 0;
-            let heapptr: *mut PhysicalAddress= &mut heapbase;
-        let r = (boot_services.allocate_pages)(ALLOCATE_ANY_PAGES, LOADER_DATA, BOOT_HEAP_SIZE / EFI_PAGE_SIZE as usize, heapptr);
-            if r.is_error() {
-                early_prints!("Could not allocated HEAP\n", 0);
-                return -1;
+            match platforms::efi::setup_heap(boot_services, BOOT_HEAP_SIZE) {
+                Some((base, size)) => {
+                    early_prints!("HEAP successfully allocated in EFI\n", 0);
+                    start_of_heap = base as u64;
+                    heap_size = size;
+                }
+                None => {
+                    early_prints!("Could not allocated HEAP\n", 0);
+                    return -1;
+                }
             }
-            else {
-                early_prints!("HEAP successfully allocated in EFI\n", 0);
-            }
-            start_of_heap = heapbase as u64;
             asm!(
                 "mov {x}, sp",
                 x = out(reg) end_of_stack
@@ -200,33 +209,30 @@ pub extern "C"  fn rrt0_entry(x0: u64, x1: u64, x2: u64, x3: u64, x4: u64, x5: u
         start_of_heap = end_of_stack;
     }
 
+    // the early buffer doesn't need the heap: anything printed from now on is kept
+    early_prints!("about to set tty_earlydev, TTY_BUFFER at %...", core::ptr::addr_of!(TTY_BUFFER) as *const u8 as u64);
+    TTYBuffer::register_static(core::ptr::addr_of_mut!(TTY_BUFFER) as *mut u8, 4096);
+    semihosting::init();
+    earlycon::register();
+    early_prints!("done.\n", core::ptr::addr_of!(TTY_BUFFER) as *const u8 as u64);
+
     // HEAP preparation
     early_prints!("HEAP to be initialized at %\n", start_of_heap as u64);
-    heap::heap_init(start_of_heap as usize, BOOT_HEAP_SIZE);
-    early_prints!("HEAP initialized with % bytes\n", BOOT_HEAP_SIZE as u64);
+    heap::heap_init(start_of_heap as usize, heap_size);
+    early_prints!("HEAP initialized with % bytes\n", heap_size as u64);
+    debug!("boot heap: {:#x} bytes at {:#x}", heap_size, start_of_heap);
 
     /*  Now that the boot HEAP is available to create Rust structs and things,
         we can use "object orientation" for clearer logic
     */
 
-    let tty_earlydev = TTYBuffer {
-        buffer: core::ptr::addr_of_mut!(TTY_BUFFER) as *mut u8,
-        capacity: 4096,
-        current: 0,
-        count: 0,
-    };
-
-    early_prints!("about to set tty_earlydev, TTY_BUFFER at %...", core::ptr::addr_of!(TTY_BUFFER) as *const u8 as u64);
-    log::set_target(Some(Box::new(tty_earlydev)));
-    early_prints!("done.\n", core::ptr::addr_of!(TTY_BUFFER) as *const u8 as u64);
-
     let information = PlatformInfo { 
         image_base: load_address, 
         image_end: end_of_image,
         boot_stack_top: end_of_stack, 
         boot_stack_capacity: 65536,
         boot_heap_base: start_of_heap,
-        boot_heap_capacity: BOOT_HEAP_SIZE,
+        boot_heap_capacity: heap_size,
         runtime_context: rc,
         x0_at_startup: x0,
         x1_at_startup: x1,
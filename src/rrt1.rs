@@ -8,18 +8,32 @@ use core::panic::PanicInfo;
 use core::hint;
 
 use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
 
-use crate::drivers::NS16550Output;
-use crate::drivers::PL011Output;
+use crate::drivers::RamoopsLogger;
+use crate::drivers::registry;
+use crate::drivers;
 use crate::dt;
 use crate::log;
 use crate::log::Logger;
 
-use crate::platforms::PlatformOperations;
-use crate::println;
+use crate::RuntimeContext;
+use crate::cmdline;
+use crate::heap;
+use crate::heap::OomPolicy;
+use crate::platforms;
+use crate::platforms::{PlatformInfo, PlatformOperations};
+use crate::{println, info, debug, warn};
 use crate::dt::DeviceTree;
+use crate::dt::Region;
 use crate::dt::read_two_items;
+use crate::frames;
 use crate::run::run;
+use crate::monitor;
+use crate::semihosting;
+use crate::earlycon;
+use crate::backtrace;
 
 use fdt_rs::base::DevTree;
 use fdt_rs::index::DevTreeIndex;
@@ -42,6 +56,7 @@ fn on_panic(_info: &PanicInfo) -> !
     // Keep println as a best-effort secondary path once a full logger is active.
     println!("Panic!!");
     println!("{:?}", _info.message());
+    backtrace::print();
     
     loop {
         hint::spin_loop();
@@ -55,20 +70,69 @@ fn on_panic(_info: &PanicInfo) -> !
 {
     println!("Panic!!");
     println!("{:?}", _info.message());
+    backtrace::print();
     
     loop {
         hint::spin_loop();
     }
 }
 // Indexing large QEMU DTBs may require significantly more temporary storage.
-static mut SCRATCHPAD: [u8; 524288] = [0; 524288];
+const SCRATCHPAD_SIZE: usize = 524288;
+static mut SCRATCHPAD: [u8; SCRATCHPAD_SIZE] = [0; SCRATCHPAD_SIZE];
+
+/* Bare metal starts with the boot HEAP placed right after the stack.
+   Once the memory nodes are known, the free RAM seeds the page-frame allocator
+   and the heap grows from it on demand.
+   The boot HEAP stays in the allocator so earlier allocations remain valid. */
+fn switch_to_runtime_heap(information: &PlatformInfo, devt: &DeviceTree, fdt: u64, ramoops: Option<&Region>) {
+
+    let mut holes: Vec<Region> = Vec::new();
+    holes.push(Region { base: information.image_base, size: information.image_end - information.image_base });
+    holes.push(Region { 
+        base: information.boot_stack_top - information.boot_stack_capacity as u64, 
+        size: information.boot_stack_capacity as u64 
+    });
+    holes.push(Region { base: information.boot_heap_base, size: information.boot_heap_capacity as u64 });
+    holes.push(Region { base: fdt, size: devt.devtree.totalsize() as u64 });
+    holes.append(&mut devt.get_memreserve());
+    holes.append(&mut devt.get_reserved_memory());
+    // ramoops= may designate a range the device tree doesn't reserve
+    holes.extend(ramoops.copied());
+
+    for r in devt.get_memory() {
+        frames::add_range(r.base, r.size);
+    }
+    for hole in &holes {
+        frames::reserve(hole.base, hole.size);
+    }
+    frames::dump();
+
+    heap::heap_set_grow(Some(frames::heap_grow));
+}
+
+/* ramoops=<base>,<size> on the command line, or a ramoops reserved-memory node */
+fn ramoops_region(devt: &DeviceTree) -> Option<Region> {
+    if let Some((base, size)) = cmdline::get_value("ramoops").and_then(|v| v.split_once(',')) {
+        if let (Some(base), Some(size)) = (cmdline::parse_u64(base), cmdline::parse_u64(size)) {
+            return Some(Region { base, size });
+        }
+    }
+    devt.get_reserved_memory_by_compatible(drivers::RAMOOPS)
+}
 
 #[allow(dead_code)]
-pub  fn rrt1_entry(mut platform: Box<dyn PlatformOperations>) -> i64 
+pub  fn rrt1_entry(mut platform: Box<dyn PlatformOperations<'static>>) -> i64 
 {
     // we have platform ownership here
     early_prints!("rr1_entry()\n", 0);
 
+    platforms::set_current(&platform);
+    heap::set_oom_policy(platform.oom_policy());
+    if let Some(line) = platform.get_cmdline() {
+        cmdline::set(line);
+        earlycon::setup_from_cmdline();
+    }
+
     let information = platform.get_info();
 
     println!(r#"Hello from Rust Runtime phase 1: {}."#, platform.get_name());
@@ -90,9 +154,10 @@ pub  fn rrt1_entry(mut platform: Box<dyn PlatformOperations>) -> i64
 
     early_prints!("FDT @%\n", fdt);
 
-    if fdt == 0 {
+    let devicetree: Option<Box<DeviceTree>> = if fdt == 0 {
         early_prints!("Use default platform TTY as FDT is not known\n", 0);
         platform.set_boot_tty();
+        None
     }
     else {
 
@@ -121,6 +186,11 @@ pub  fn rrt1_entry(mut platform: Box<dyn PlatformOperations>) -> i64
             }
 
             early_prints!("\nDone.\n", 0);
+
+            // helps sizing SCRATCHPAD for a board
+            if let Ok(layout) = DevTreeIndex::get_layout(&fdt) {
+                debug!("FDT index uses {} of {} scratchpad bytes", layout.size() + layout.align(), SCRATCHPAD_SIZE);
+            }
             
             let slice = SCRATCHPAD.as_mut_slice();
             let index = match DevTreeIndex::new(fdt, slice) {
@@ -155,10 +225,9 @@ pub  fn rrt1_entry(mut platform: Box<dyn PlatformOperations>) -> i64
         }
         early_prints!("Checking memory reservations entries\n", 0);
         println!("memory reservations:");
-        for rsv in devt.devtree.reserved_entries()
+        for r in devt.get_memreserve()
         {
-            let _ = rsv;
-            println!("    <reserved entry>");
+            println!("    {:#012x}-{:#012x}", r.base, r.base + r.size);
         }
         early_prints!("Checking memreserve\n", 0);
         let memreserve_node= devt.get_node_by_path("/").unwrap();
@@ -169,61 +238,142 @@ pub  fn rrt1_entry(mut platform: Box<dyn PlatformOperations>) -> i64
             }
         }
 
+        if cmdline::get().is_empty() {
+            if let Some(chosen) = devt.get_node_by_path("/chosen") {
+                if let Some(bootargs) = devt.get_prop_by_name(&chosen, "bootargs") {
+                    if let Ok(line) = bootargs.str() {
+                        cmdline::set(String::from(line));
+                        earlycon::setup_from_cmdline();
+                    }
+                }
+            }
+        }
+
+        // the persistent log must be kept away from the allocators before it is replayed
+        let ramoops = ramoops_region(&devt);
+        if let Some(region) = &ramoops {
+            platform.reserve_frames(region.base, region.size);
+        }
+
+        // secure payloads do not own the RAM described in /memory
+        if information.runtime_context != RuntimeContext::EFI && !platform.is_secure() {
+            switch_to_runtime_heap(information, &devt, fdt, ramoops.as_ref());
+        }
+
+        // recording starts now, with the heap able to hold the previous log:
+        // a board without a usable console still leaves a trace
+        let mut persistent = false;
+        if let Some(region) = &ramoops {
+            match RamoopsLogger::register_static(region.base, region.size) {
+                Some((sequence, sink)) => {
+                    // this boot's record starts with what was printed so far
+                    log::write_fmt_to(sink, format_args!("{}\n", log::get_unprinted()));
+                    info!("persistent log #{} at {:#x}, {} bytes", sequence, region.base, region.size);
+                    persistent = true;
+                }
+                None => warn!("persistent log region at {:#x} is too small", region.base),
+            }
+        }
+
         let stdout_parent = "chosen";
         //if platform.is_secure() {
         //    stdout_parent = "secure-chosen";
         //}
         let stdout_node= crate::Platform::get_stdout(&devt, stdout_parent);
-        let stdout = match stdout_node {
-            None => panic!("stdout-path target node not found"),
-            Some(ref c) => c
-        };
-
-        let _path = dt::to_path(stdout);
-        early_prints!("stdout-path from node=$\n", _path.as_ptr() as u64);
-
-        let compatible_prop = devt.get_prop_by_name(stdout, "compatible").unwrap();
-        let compatible_strings = compatible_prop.iter_str();
-
 
-        let mmio = devt.parse_mmio(stdout);
-        let m=&mmio[0];
-        println!("    mmio={:#012x}-{:#012x}", m.base, m.base+m.size); 
-        early_prints!("    mmio base %", m.base);
-        early_prints!(" - %\n",  m.base+m.size);
+        let bound = registry::bind_all(&devt);
+        debug!("{} devices bound", bound);
 
-        #[allow(unused_assignments)]
+        // console=<driver name> overrides stdout-path with a device found by probing
         let mut tty: Option<Box<dyn Logger>> = None;
-        
+        if let Some(driver) = cmdline::get_value("console") {
+            tty = registry::take_console_of(&devt, driver).or_else(|| platform.get_console(driver));
+            if tty.is_none() {
+                warn!("console={}: no such console, using stdout-path", driver);
+            }
+        }
+        // the stdout-path options are applied by the driver when it starts the console
+        let stdout_path = match stdout_node {
+            Some((ref stdout, stdout_options)) => {
+                let path = dt::to_path(stdout);
+                early_prints!("stdout-path from node=$\n", path.as_ptr() as u64);
+                if tty.is_none() {
+                    tty = registry::take_console(&devt, &path, stdout_options);
+                }
+                path
+            }
+            None => String::from("stdout-path target"),
+        };
+        match tty {
+            None => {
+                early_prints!("no driver found\n", 0);
+                if !persistent {
+                    panic!("No driver found for {}", stdout_path)
+                }
+                // the persistent log is all there is: keep the boot consoles and go on rather than lose it
+                warn!("No driver found for {}, output only goes to the persistent log", stdout_path);
+            }
+            Some(tty) => {
+                early_prints!("About to change the driver\n", 0);
+
+                let prev = log::get_unprinted();
+                log::set_target(Some(tty));
+                // the early console or EFI ConOut already showed what is replayed below,
+                // both usually write to the serial port that just became the console
+                let mut shown = false;
+                if !cmdline::has_flag("keep_bootcon") {
+                    shown = earlycon::unregister();
+                    shown |= platform.unregister_boot_tty();
+                }
 
-        if let Some(compat) = PL011Output::is_compatible(compatible_strings.clone()) {
-            tty =  PL011Output::new(compat, m.base);
-            early_prints!("pl011 driver created\n", 0);
+                RamoopsLogger::replay_previous();
+                // the other sinks (semihosting...) got the output as it was printed
+                if !shown {
+                    log::write_fmt_to(log::CONSOLE_SINK, format_args!("{}\n", &prev));
+                }
+            }
         }
-        else if let Some(compat) = NS16550Output::is_compatible(compatible_strings.clone()) {
-            tty =  NS16550Output::new( compat, m.base, &devt, &stdout);
+        Some(devt)
+    }; /* fdt vs acpi */
 
-        } else {
-            early_prints!("no driver found\n", 0);
-            panic!("No driver found")
+    if cmdline::has_flag("semihosting") {
+        semihosting::enable();
+        semihosting::init();
+    }
+    if let Some(spec) = cmdline::get_value("log") {
+        log::set_filter(spec);
+    }
+    // a verbose log filter can go to the persistent log without flooding the console
+    if let Some(level) = cmdline::get_value("console_level").and_then(log::Level::from_name) {
+        log::set_sink_level(log::CONSOLE_SINK, level);
+    }
+    if !cmdline::get().is_empty() {
+        info!("command line: {}", cmdline::get());
+    }
+    if let Some(policy) = cmdline::get_value("oom").and_then(OomPolicy::from_name) {
+        // a reset that returns would only be a halt
+        if policy == OomPolicy::Reset && !platform.can_reset() {
+            warn!("oom=reset: {} can't reset, ignored", platform.get_name());
         }
-        
-        early_prints!("About to change the driver\n", 0);
-        
-        let prev = log::get_unprinted();
-        log::set_target(tty);
-        println!("{}", &prev);
-    } /* fdt vs acpi */
+        else {
+            heap::set_oom_policy(policy);
+        }
+    }
 
     #[allow(unused_assignments)]
     let mut result : i64 = 0;
 
-    result = run(&platform);
+    if cmdline::has_flag("monitor") {
+        result = monitor::monitor(&platform, devicetree.as_deref());
+    }
+    else {
+        result = run(&platform);
+    }
 
     platform.pre_stop();
 
     // the following may not return (S-EL1 for instance, or poweroff from EL1)
-    platform.stop();
+    platform.stop(result);
 
     if platform.can_return() {
         // If makes sense, return to calling environment (U-Boot or EFI).
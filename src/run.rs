@@ -16,177 +16,68 @@ use crate::print;
 
 use crate::processor;
 use crate::processor::ExceptionFrame;
+use crate::exception;
 
 
-global_asm!("
-.align 11
-exception_table:
-
-    sub     sp, sp, #304
-    stp     x0, x1, [sp]
-    stp     x2, x3, [sp, #16]
-
-    adr     x0, reloc_offset
-    ldr     x0, [x0]
-    adr     x1, sync_excetion_same_el_sp0
-    sub     x2, x1, x0
-    adr     x1, trampoline
-    sub     x1, x1, x0
-
-    br      x1 // trampoline
-    
-reloc_offset:
-    .quad   0
-
-. = exception_table + 0x200
+/*
+The vector tables of EL1, EL2 and EL3 are instances of one template, the EL
+being the macro parameter. The 16 entries save x0-x3 and jump to the common
+trampoline with the Rust handler in x2; the trampoline completes the
+ExceptionFrame and returns from the exception once the handler is done.
+
+print_regs copies the synchronous entries in place of the firmware ones, which
+keeps its interrupt handling: the addresses are computed with adr and corrected
+with reloc_offset (0 when VBAR points to the table itself), which is part of
+the first entry so that it is copied with it.
+*/
+global_asm!(r"
+.macro vector_entry el, offset, handler
+. = exception_table_el\el + \offset
     sub     sp, sp, #304
     stp     x0, x1, [sp]
     stp     x2, x3, [sp, #16]
 
-    adr     x0, reloc_offset
+    adr     x0, reloc_offset_el\el
     ldr     x0, [x0]
-    adr     x1, sync_excetion_same_el_spx
+    adr     x1, \handler
     sub     x2, x1, x0
-    adr     x1, trampoline
+    adr     x1, trampoline_el\el
     sub     x1, x1, x0
 
     br      x1 // trampoline
+.endm
 
-
-. = exception_table + 0x800
-
-trampoline:
-    stp     x4, x5, [sp, #32]
-    stp     x6, x7, [sp, #48]
-    stp     x8, x9, [sp, #64]
-    stp     x10, x11, [sp, #80]
-    stp     x12, x13, [sp, #96]
-    stp     x14, x15, [sp, #112]
-    stp     x16, x17, [sp, #128]
-    stp     x18, x19, [sp, #144]
-    stp     x20, x21, [sp, #160]
-    stp     x22, x23, [sp, #176]
-    stp     x24, x25, [sp, #192]
-    stp     x26, x27, [sp, #208]
-    stp     x28, x29, [sp, #224]
-
-    # x21 = old_sp
-    add     x21, sp, #304
-    stp     x30, x21, [sp, #240]
-    
-    # preserve flags, return address and syndrome
-    mrs     x22, elr_el1
-    mrs     x23, spsr_el1
-    stp     x22, x23, [sp, #256]
-    mrs     x24, esr_el1
-    str     x24, [sp, #272]
-
-    # make a new stack frame for backtrace to work in the future
-    stp     x29, x22, [sp, #288]                                                                                               
-    add     x29, sp, #288
-
-
-    mov     x8, x2               // get the handler address in x8
-    mov     x0, sp               // get Exception in x0
-
-    blr     x8
-
-    msr     daifset, #0xf
-
-    #restore returning environment
-    ldp     x22, x23, [sp, #256]
-    msr     elr_el1, x22
-    msr     spsr_el1, x23
-
-    ldp     x0, x1, [sp]
-    ldp     x2, x3, [sp, #16]
-    ldp     x4, x5, [sp, #32]
-    ldp     x6, x7, [sp, #48]
-    ldp     x8, x9, [sp, #64]
-    ldp     x10, x11, [sp, #80]
-    ldp     x12, x13, [sp, #96]
-    ldp     x14, x15, [sp, #112]
-    ldp     x16, x17, [sp, #128]
-    ldp     x18, x19, [sp, #144]
-    ldp     x20, x21, [sp, #160]  
-    ldp     x22, x23, [sp, #176]  
-    ldp     x24, x25, [sp, #192]  
-    ldp     x26, x27, [sp, #208]  
-    ldp     x28, x29, [sp, #224]  
-    ldr     x30, [sp, #240]
-
-    add     sp, sp, #304
-
-    eret
-
-");
-
-
-global_asm!("
+.macro exception_table el
 .align 11
-exception_table_el2:
+exception_table_el\el:
 
-    sub     sp, sp, #304
-    stp     x0, x1, [sp]
-    stp     x2, x3, [sp, #16]
+    vector_entry \el, 0x000, sync_excetion_same_el_sp0
 
-    adr     x0, reloc_offset_el2
-    ldr     x0, [x0]
-    adr     x1, sync_excetion_same_el_sp0
-    sub     x2, x1, x0
-    adr     x1, trampoline_el2
-    sub     x1, x1, x0
-
-    br      x1 // trampoline
-    
-reloc_offset_el2:
+reloc_offset_el\el:
     .quad   0
 
-. = exception_table_el2 + 0x200
-    sub     sp, sp, #304
-    stp     x0, x1, [sp]
-    stp     x2, x3, [sp, #16]
+    vector_entry \el, 0x080, irq_exception_same_el_sp0
+    vector_entry \el, 0x100, fiq_exception_same_el_sp0
+    vector_entry \el, 0x180, serror_exception_same_el_sp0
 
-    adr     x0, reloc_offset_el2
-    ldr     x0, [x0]
-    adr     x1, sync_excetion_same_el_spx
-    sub     x2, x1, x0
-    adr     x1, trampoline_el2
-    sub     x1, x1, x0
+    vector_entry \el, 0x200, sync_excetion_same_el_spx
+    vector_entry \el, 0x280, irq_exception_same_el_spx
+    vector_entry \el, 0x300, fiq_exception_same_el_spx
+    vector_entry \el, 0x380, serror_exception_same_el_spx
 
-    br      x1 // trampoline
-
-    . = exception_table_el2 + 0x400
-    sub     sp, sp, #304
-    stp     x0, x1, [sp]
-    stp     x2, x3, [sp, #16]
-
-    adr     x0, reloc_offset_el2
-    ldr     x0, [x0]
-    adr     x1, sync_excetion_lower_el_aarch64
-    sub     x2, x1, x0
-    adr     x1, trampoline_el2
-    sub     x1, x1, x0
-
-    br      x1 // trampoline
-
-    . = exception_table_el2 + 0x600
-    sub     sp, sp, #304
-    stp     x0, x1, [sp]
-    stp     x2, x3, [sp, #16]
-
-    adr     x0, reloc_offset_el2
-    ldr     x0, [x0]
-    adr     x1, sync_excetion_lower_el_aarch32
-    sub     x2, x1, x0
-    adr     x1, trampoline_el2
-    sub     x1, x1, x0
+    vector_entry \el, 0x400, sync_excetion_lower_el_aarch64
+    vector_entry \el, 0x480, irq_exception_lower_el_aarch64
+    vector_entry \el, 0x500, fiq_exception_lower_el_aarch64
+    vector_entry \el, 0x580, serror_exception_lower_el_aarch64
 
-    br      x1 // trampoline
+    vector_entry \el, 0x600, sync_excetion_lower_el_aarch32
+    vector_entry \el, 0x680, irq_exception_lower_el_aarch32
+    vector_entry \el, 0x700, fiq_exception_lower_el_aarch32
+    vector_entry \el, 0x780, serror_exception_lower_el_aarch32
 
-. = exception_table_el2 + 0x800
+. = exception_table_el\el + 0x800
 
-trampoline_el2:
+trampoline_el\el:
     stp     x4, x5, [sp, #32]
     stp     x6, x7, [sp, #48]
     stp     x8, x9, [sp, #64]
@@ -201,150 +92,19 @@ trampoline_el2:
     stp     x26, x27, [sp, #208]
     stp     x28, x29, [sp, #224]
 
-    # x21 = old_sp
+    // x21 = old_sp
     add     x21, sp, #304
     stp     x30, x21, [sp, #240]
-    
-    # preserve flags, return address and syndrome
-    mrs     x22, elr_el2
-    mrs     x23, spsr_el2
-    stp     x22, x23, [sp, #256]
-    mrs     x24, esr_el2
-    str     x24, [sp, #272]
-
-    # make a new stack frame for backtrace to work in the future
-    stp     x29, x22, [sp, #288]                                                                                               
-    add     x29, sp, #288
-
-
-    mov     x8, x2               // get the handler address in x8
-    mov     x0, sp               // get Exception in x0
-
-    blr     x8
-
-    msr     daifset, #0xf
-
-    #restore returning environment
-    ldp     x22, x23, [sp, #256]
-    msr     elr_el2, x22
-    msr     spsr_el2, x23
-
-    ldp     x0, x1, [sp]
-    ldp     x2, x3, [sp, #16]
-    ldp     x4, x5, [sp, #32]
-    ldp     x6, x7, [sp, #48]
-    ldp     x8, x9, [sp, #64]
-    ldp     x10, x11, [sp, #80]
-    ldp     x12, x13, [sp, #96]
-    ldp     x14, x15, [sp, #112]
-    ldp     x16, x17, [sp, #128]
-    ldp     x18, x19, [sp, #144]
-    ldp     x20, x21, [sp, #160]  
-    ldp     x22, x23, [sp, #176]  
-    ldp     x24, x25, [sp, #192]  
-    ldp     x26, x27, [sp, #208]  
-    ldp     x28, x29, [sp, #224]  
-    ldr     x30, [sp, #240]
-
-    add     sp, sp, #304
-
-    eret
-
-");
-
-
-global_asm!("
-.align 11
-exception_table_el3:
-
-    sub     sp, sp, #304
-    stp     x0, x1, [sp]
-    stp     x2, x3, [sp, #16]
 
-    adr     x0, reloc_offset_el3
-    ldr     x0, [x0]
-    adr     x1, sync_excetion_same_el_sp0
-    sub     x2, x1, x0
-    adr     x1, trampoline_el3
-    sub     x1, x1, x0
-
-    br      x1 // trampoline
-    
-reloc_offset_el3:
-    .quad   0
-
-. = exception_table_el3 + 0x200
-    sub     sp, sp, #304
-    stp     x0, x1, [sp]
-    stp     x2, x3, [sp, #16]
-
-    adr     x0, reloc_offset_el3
-    ldr     x0, [x0]
-    adr     x1, sync_excetion_same_el_spx
-    sub     x2, x1, x0
-    adr     x1, trampoline_el3
-    sub     x1, x1, x0
-
-    br      x1 // trampoline
-
-    . = exception_table_el3 + 0x400
-    sub     sp, sp, #304
-    stp     x0, x1, [sp]
-    stp     x2, x3, [sp, #16]
-
-    adr     x0, reloc_offset_el3
-    ldr     x0, [x0]
-    adr     x1, sync_excetion_lower_el_aarch64
-    sub     x2, x1, x0
-    adr     x1, trampoline_el3
-    sub     x1, x1, x0
-
-    br      x1 // trampoline
-
-    . = exception_table_el3 + 0x600
-    sub     sp, sp, #304
-    stp     x0, x1, [sp]
-    stp     x2, x3, [sp, #16]
-
-    adr     x0, reloc_offset_el3
-    ldr     x0, [x0]
-    adr     x1, sync_excetion_lower_el_aarch32
-    sub     x2, x1, x0
-    adr     x1, trampoline_el3
-    sub     x1, x1, x0
-
-    br      x1 // trampoline
-
-. = exception_table_el3 + 0x800
-
-trampoline_el3:
-    stp     x4, x5, [sp, #32]
-    stp     x6, x7, [sp, #48]
-    stp     x8, x9, [sp, #64]
-    stp     x10, x11, [sp, #80]
-    stp     x12, x13, [sp, #96]
-    stp     x14, x15, [sp, #112]
-    stp     x16, x17, [sp, #128]
-    stp     x18, x19, [sp, #144]
-    stp     x20, x21, [sp, #160]
-    stp     x22, x23, [sp, #176]
-    stp     x24, x25, [sp, #192]
-    stp     x26, x27, [sp, #208]
-    stp     x28, x29, [sp, #224]
-
-    # x21 = old_sp
-    add     x21, sp, #304
-    stp     x30, x21, [sp, #240]
-    
-    # preserve flags, return address and syndrome
-    mrs     x22, elr_el3
-    mrs     x23, spsr_el3
+    // preserve flags, return address and syndrome
+    mrs     x22, elr_el\el
+    mrs     x23, spsr_el\el
     stp     x22, x23, [sp, #256]
-    mrs     x24, esr_el3
+    mrs     x24, esr_el\el
     str     x24, [sp, #272]
 
-    # make a new stack frame for backtrace to work in the future
-    stp     x29, x22, [sp, #288]                                                                                               
+    // frame record (interrupted x29, ELR): backtraces continue through the exception
+    stp     x29, x22, [sp, #288]
     add     x29, sp, #288
 
 
@@ -355,10 +115,10 @@ trampoline_el3:
 
     msr     daifset, #0xf
 
-    #restore returning environment
+    // restore returning environment
     ldp     x22, x23, [sp, #256]
-    msr     elr_el3, x22
-    msr     spsr_el3, x23
+    msr     elr_el\el, x22
+    msr     spsr_el\el, x23
 
     ldp     x0, x1, [sp]
     ldp     x2, x3, [sp, #16]
@@ -370,22 +130,26 @@ trampoline_el3:
     ldp     x14, x15, [sp, #112]
     ldp     x16, x17, [sp, #128]
     ldp     x18, x19, [sp, #144]
-    ldp     x20, x21, [sp, #160]  
-    ldp     x22, x23, [sp, #176]  
-    ldp     x24, x25, [sp, #192]  
-    ldp     x26, x27, [sp, #208]  
-    ldp     x28, x29, [sp, #224]  
+    ldp     x20, x21, [sp, #160]
+    ldp     x22, x23, [sp, #176]
+    ldp     x24, x25, [sp, #192]
+    ldp     x26, x27, [sp, #208]
+    ldp     x28, x29, [sp, #224]
     ldr     x30, [sp, #240]
 
     add     sp, sp, #304
 
     eret
+.endm
 
+    exception_table 1
+    exception_table 2
+    exception_table 3
 ");
 
 extern "C" {
-    fn exception_table() -> !;
-    fn reloc_offset() -> !;
+    fn exception_table_el1() -> !;
+    fn reloc_offset_el1() -> !;
     fn exception_table_el2() -> !;
     fn reloc_offset_el2() -> !;
     fn exception_table_el3() -> !;
@@ -398,8 +162,7 @@ extern "C" {
 
 #[export_name = "sync_excetion_same_el_sp0"]
 extern "C" fn sync_excetion_same_el_sp0( ef : &mut ExceptionFrame) -> u64 {
-    let ec = (ef.esr >> 26) & 0x3f;
-    panic!("Unsupported sync_excetion_same_el_sp0 {:#x} at {:#x}", ec, ef.elr);
+    exception::fatal("sync_excetion_same_el_sp0", ef);
 }
 
 #[export_name = "sync_excetion_same_el_spx"]
@@ -411,7 +174,7 @@ extern "C" fn sync_excetion_same_el_spx( ef : &mut ExceptionFrame) -> u64 {
         ef.elr += 4;
     }
     else {
-        panic!("Unsupported sync_excetion_same_el_spx {:#x} at {:#x}", ec, ef.elr);
+        exception::fatal("sync_excetion_same_el_spx", ef);
     }
     return 0;
 }
@@ -425,17 +188,39 @@ extern "C" fn sync_excetion_lower_el_aarch64( ef : &mut ExceptionFrame) -> u64 {
         ef.elr += 4;
     }
     else {
-        panic!("Unsupported sync_excetion_same_el_spx {:#x} at {:#x}", ec, ef.elr);
+        exception::fatal("sync_excetion_lower_el_aarch64", ef);
     }
     return 0;
 }
 
 #[export_name = "sync_excetion_lower_el_aarch32"]
 extern "C" fn sync_excetion_lower_el_aarch32( ef : &mut ExceptionFrame) -> u64 {
-    let ec = (ef.esr >> 26) & 0x3f;
-    panic!("Unsupported sync_excetion_lower_el_aarch32 {:#x} at {:#x}", ec, ef.elr);
+    exception::fatal("sync_excetion_lower_el_aarch32", ef);
+}
+
+/* barekit does not enable interrupts: any IRQ, FIQ or SError is reported and fatal */
+macro_rules! fatal_handler {
+    ($name:ident, $report:path) => {
+        #[export_name = stringify!($name)]
+        extern "C" fn $name(ef: &mut ExceptionFrame) -> u64 {
+            $report(stringify!($name), ef);
+        }
+    };
 }
 
+fatal_handler!(irq_exception_same_el_sp0, exception::unexpected_interrupt);
+fatal_handler!(fiq_exception_same_el_sp0, exception::unexpected_interrupt);
+fatal_handler!(serror_exception_same_el_sp0, exception::fatal);
+fatal_handler!(irq_exception_same_el_spx, exception::unexpected_interrupt);
+fatal_handler!(fiq_exception_same_el_spx, exception::unexpected_interrupt);
+fatal_handler!(serror_exception_same_el_spx, exception::fatal);
+fatal_handler!(irq_exception_lower_el_aarch64, exception::unexpected_interrupt);
+fatal_handler!(fiq_exception_lower_el_aarch64, exception::unexpected_interrupt);
+fatal_handler!(serror_exception_lower_el_aarch64, exception::fatal);
+fatal_handler!(irq_exception_lower_el_aarch32, exception::unexpected_interrupt);
+fatal_handler!(fiq_exception_lower_el_aarch32, exception::unexpected_interrupt);
+fatal_handler!(serror_exception_lower_el_aarch32, exception::fatal);
+
 
 
 
@@ -485,7 +270,7 @@ fn dump_paging_step(anchor : u64, base : u64, level: usize, level_size: usize) {
 
 
 #[allow(dead_code)]
-fn dump_paging() {
+pub fn dump_paging() {
     let anchor = processor::get_anchor_for(0);
     let info = processor::paging_get_low_mem_paging();
     dump_paging_step(anchor, 0, info.0 as usize, info.1);
@@ -495,7 +280,7 @@ fn dump_paging() {
 static mut PREVIOUS_VBAR: u64 = 0;
 
 #[allow(dead_code)]
-fn generate_cpu_vobj() {
+pub fn generate_cpu_vobj() {
     print!("\n");
     print!("-vobj 'CPU#name=\"\";");
 
@@ -928,7 +713,7 @@ pub fn print_regs(platform:&Box<dyn PlatformOperations>) -> i64 {
         PREVIOUS_VBAR = processor::get_vbar();
         
         match current_el {
-            1 => barekit_vbar = exception_table as u64,
+            1 => barekit_vbar = exception_table_el1 as u64,
             2 => barekit_vbar = exception_table_el2 as u64,
             3 => barekit_vbar = exception_table_el3 as u64,
             _ => panic!("Invalid EL")
@@ -1009,8 +794,8 @@ pub fn print_regs(platform:&Box<dyn PlatformOperations>) -> i64 {
 
             let offset ;
             if current_el == 1 {
-                offset = ((reloc_offset as u64) - (exception_table as u64)+ PREVIOUS_VBAR) as *mut u64;
-                *offset = PREVIOUS_VBAR - exception_table as u64;
+                offset = ((reloc_offset_el1 as u64) - (exception_table_el1 as u64)+ PREVIOUS_VBAR) as *mut u64;
+                *offset = PREVIOUS_VBAR - exception_table_el1 as u64;
             } 
             else if current_el == 2 {
                 offset = ((reloc_offset_el2 as u64) - (exception_table_el2 as u64)+ PREVIOUS_VBAR) as *mut u64;
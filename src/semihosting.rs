@@ -0,0 +1,208 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+Arm semihosting (QEMU -semihosting, models, debuggers).
+
+The host is reached with HLT #0xF000: an unconnected HLT is an undefined
+instruction, so nothing is issued until semihosting is enabled, either at build
+time with the semihosting feature or with the "semihosting" command line word.
+Once enabled:
+- a log sink writes the console output on the host (SYS_WRITE0/SYS_WRITEC),
+- PlatformOperations::stop and exit end the VM with the status (SYS_EXIT_EXTENDED),
+- host files can be read (SYS_OPEN/SYS_FLEN/SYS_READ/SYS_CLOSE).
+*/
+
+use core::arch::asm;
+use core::{fmt, ptr};
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::log;
+use crate::log::{Level, TTY};
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_READ: u64 = 0x06;
+const SYS_FLEN: u64 = 0x0C;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+// SYS_OPEN modes, as fopen() strings
+const OPEN_MODE_RB: u64 = 1;
+
+// SYS_WRITE0 needs a NUL terminated string: output is sent in chunks
+const WRITE_CHUNK: usize = 128;
+
+static mut ENABLED: bool = cfg!(feature = "semihosting");
+static mut SINK_INSTALLED: bool = false;
+
+pub fn is_enabled() -> bool {
+    unsafe { ENABLED }
+}
+
+pub fn enable() {
+    unsafe {
+        ENABLED = true;
+    }
+}
+
+/* the caller checked is_enabled() */
+unsafe fn call(operation: u64, parameter: u64) -> u64 {
+    let result: u64;
+    asm!(
+        "hlt #0xf000",
+        inout("x0") operation => result,
+        in("x1") parameter,
+        options(nostack)
+    );
+    result
+}
+
+pub struct SemihostingLogger {
+}
+
+// storage for the sink, registered without the heap
+static mut STATIC_LOGGER: SemihostingLogger = SemihostingLogger {};
+
+impl fmt::Write for SemihostingLogger {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !is_enabled() {
+            return Ok(());
+        }
+        if s.len() == 1 {
+            let c = s.as_bytes()[0];
+            unsafe { call(SYS_WRITEC, ptr::addr_of!(c) as u64) };
+            return Ok(());
+        }
+        let mut chunk = [0u8; WRITE_CHUNK + 1];
+        let mut length = 0;
+        for &c in s.as_bytes().iter().filter(|&&c| c != 0) {
+            chunk[length] = c;
+            length += 1;
+            if length == WRITE_CHUNK {
+                chunk[length] = 0;
+                unsafe { call(SYS_WRITE0, chunk.as_ptr() as u64) };
+                length = 0;
+            }
+        }
+        if length > 0 {
+            chunk[length] = 0;
+            unsafe { call(SYS_WRITE0, chunk.as_ptr() as u64) };
+        }
+        Ok(())
+    }
+}
+
+impl TTY for SemihostingLogger {
+    fn get_unprinted(&self) -> String {
+        return String::from("");
+    }
+}
+
+/// Mirrors the log on the host console once semihosting is enabled, no heap needed.
+pub fn init() {
+    unsafe {
+        if !is_enabled() || SINK_INSTALLED {
+            return;
+        }
+        SINK_INSTALLED = log::add_static_sink(&mut *ptr::addr_of_mut!(STATIC_LOGGER), Level::Trace).is_some();
+    }
+}
+
+/// Ends the simulation with `status` as the host process exit code.
+/// Returns if semihosting is not enabled.
+pub fn exit(status: i64) {
+    if !is_enabled() {
+        return;
+    }
+    let block: [u64; 2] = [ADP_STOPPED_APPLICATION_EXIT, status as u64];
+    unsafe {
+        call(SYS_EXIT_EXTENDED, block.as_ptr() as u64);
+        // hosts without the extended call only report success, the block is {reason, subcode}
+        call(SYS_EXIT, block.as_ptr() as u64);
+    }
+}
+
+/// A file opened on the host.
+pub struct HostFile {
+    handle: u64,
+}
+
+impl HostFile {
+
+    /// Opens `path` on the host for reading, in binary mode.
+    pub fn open(path: &str) -> Option<HostFile> {
+        if !is_enabled() {
+            return None;
+        }
+        let mut name: Vec<u8> = Vec::with_capacity(path.len() + 1);
+        name.extend_from_slice(path.as_bytes());
+        name.push(0);
+        let block: [u64; 3] = [name.as_ptr() as u64, OPEN_MODE_RB, path.len() as u64];
+        let handle = unsafe { call(SYS_OPEN, block.as_ptr() as u64) };
+        if handle as i64 == -1 {
+            return None;
+        }
+        Some(HostFile { handle })
+    }
+
+    pub fn len(&self) -> Option<usize> {
+        let block: [u64; 1] = [self.handle];
+        let length = unsafe { call(SYS_FLEN, block.as_ptr() as u64) };
+        if length as i64 == -1 {
+            return None;
+        }
+        Some(length as usize)
+    }
+
+    /// Reads up to `buffer.len()` bytes, returning how many were read.
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let block: [u64; 3] = [self.handle, buffer.as_mut_ptr() as u64, buffer.len() as u64];
+        // the host returns the number of bytes NOT read
+        let remaining = unsafe { call(SYS_READ, block.as_ptr() as u64) } as usize;
+        buffer.len().saturating_sub(remaining)
+    }
+}
+
+impl Drop for HostFile {
+    fn drop(&mut self) {
+        let block: [u64; 1] = [self.handle];
+        unsafe { call(SYS_CLOSE, block.as_ptr() as u64) };
+    }
+}
+
+/// Reads a whole host file in a heap buffer.
+#[allow(dead_code)]
+pub fn read_file(path: &str) -> Option<Vec<u8>> {
+    let mut file = HostFile::open(path)?;
+    let length = file.len()?;
+    let mut content = alloc::vec![0u8; length];
+    if file.read(&mut content) != length {
+        return None;
+    }
+    Some(content)
+}
+
+/// Loads a host file at `address` (a DTB, a payload...), failing if it is
+/// larger than `capacity`. Returns the file size.
+pub fn load_file(path: &str, address: u64, capacity: usize) -> Option<usize> {
+    let mut file = HostFile::open(path)?;
+    let length = file.len()?;
+    if length > capacity {
+        return None;
+    }
+    let target = unsafe { core::slice::from_raw_parts_mut(address as *mut u8, length) };
+    if file.read(target) != length {
+        return None;
+    }
+    Some(length)
+}
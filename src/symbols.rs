@@ -0,0 +1,145 @@
+/*
+    SPDX-License-Identifier: Mozilla Public License 2.0
+    Copyrigth (c) 2022-2023 François-Frédéric Ozog
+
+*/
+
+/*
+Symbol table embedded in the .bksyms section, filled after the link by
+stage_symbols from the demangled map (barekit.mapsym):
+
+    +--------------------+  header, little endian u32s
+    | magic "BKSY"       |
+    | count              |
+    | table_rva          |  RVA of the section itself
+    | strings_offset     |  from the start of the section
+    +--------------------+
+    | count x {rva, name}|  sorted by rva, name is an offset in the strings
+    +--------------------+
+    | NUL terminated names
+    +--------------------+
+
+Only RVAs are stored: the image base is found from where the section is, so
+the table is right wherever the image was loaded or relocated to
+(coff_stager::relocate, EFI loader). An image that did not go through
+stage_symbols has count 0 and symbolize() returns None.
+
+The section is in every image, staged or not: its size comes from the
+BAREKIT_SYMBOLS_KB environment variable at build time (256 KiB by default,
+0 keeps only the header and no symbol is embedded).
+*/
+
+use core::ptr;
+
+use crate::print;
+
+const SYMBOLS_MAGIC: u32 = 0x5953_4b42; // "BKSY"
+const HEADER_SIZE: usize = 16;
+const DEFAULT_SYMBOLS_KB: usize = 256;
+
+/* decimal KiB, a malformed value stops the build */
+const fn table_size(kb: Option<&str>) -> usize {
+    let digits = match kb {
+        Some(text) => text.as_bytes(),
+        None => return DEFAULT_SYMBOLS_KB * 1024,
+    };
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        assert!(digits[i].is_ascii_digit(), "BAREKIT_SYMBOLS_KB must be a number of KiB");
+        value = value * 10 + (digits[i] - b'0') as usize;
+        i += 1;
+    }
+    if value == 0 { HEADER_SIZE } else { value * 1024 }
+}
+
+const SYMBOL_TABLE_SIZE: usize = table_size(option_env!("BAREKIT_SYMBOLS_KB"));
+const ENTRY_SIZE: usize = 8;
+
+#[repr(C, align(8))]
+struct SymbolTable([u8; SYMBOL_TABLE_SIZE]);
+
+/* the magic keeps the section initialized data so that it has room in the file */
+#[used]
+#[link_section = ".bksyms"]
+static mut SYMBOL_TABLE: SymbolTable = {
+    let mut table = [0u8; SYMBOL_TABLE_SIZE];
+    table[0] = b'B';
+    table[1] = b'K';
+    table[2] = b'S';
+    table[3] = b'Y';
+    SymbolTable(table)
+};
+
+/* patched after the link: always read through volatile accesses */
+fn read_u32(offset: usize) -> u32 {
+    let base = ptr::addr_of!(SYMBOL_TABLE) as *const u8;
+    unsafe { ptr::read_volatile(base.add(offset) as *const u32) }
+}
+
+fn table_base() -> u64 {
+    ptr::addr_of!(SYMBOL_TABLE) as u64
+}
+
+/// Number of embedded symbols, 0 if stage_symbols did not run.
+pub fn count() -> usize {
+    if read_u32(0) != SYMBOLS_MAGIC {
+        return 0;
+    }
+    read_u32(4) as usize
+}
+
+/// Where the image is loaded, as seen from the symbol table.
+pub fn image_base() -> Option<u64> {
+    if count() == 0 {
+        return None;
+    }
+    Some(table_base() - read_u32(8) as u64)
+}
+
+fn name_at(offset: usize) -> &'static str {
+    let strings = read_u32(12) as usize;
+    let start = strings + offset;
+    if start >= SYMBOL_TABLE_SIZE {
+        return "?";
+    }
+    let bytes = unsafe {
+        core::slice::from_raw_parts((table_base() as *const u8).add(start), SYMBOL_TABLE_SIZE - start)
+    };
+    let length = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..length]).unwrap_or("?")
+}
+
+/// The symbol containing `address` and the offset in it.
+pub fn symbolize(address: u64) -> Option<(&'static str, u64)> {
+    let count = count();
+    let rva = address.checked_sub(image_base()?)?;
+    if rva > u32::MAX as u64 {
+        return None;
+    }
+    let rva = rva as u32;
+    // last entry whose rva is <= the target
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if read_u32(HEADER_SIZE + middle * ENTRY_SIZE) <= rva {
+            low = middle + 1;
+        }
+        else {
+            high = middle;
+        }
+    }
+    if low == 0 {
+        return None;
+    }
+    let entry = HEADER_SIZE + (low - 1) * ENTRY_SIZE;
+    let name = name_at(read_u32(entry + 4) as usize);
+    Some((name, (rva - read_u32(entry)) as u64))
+}
+
+/// Prints " name+0xoffset" when the address is known.
+pub fn print_symbol(address: u64) {
+    if let Some((name, offset)) = symbolize(address) {
+        print!(" {}+{:#x}", name, offset);
+    }
+}